@@ -3,8 +3,14 @@ use reqwest::Client;
 use std::fs;
 use std::path::PathBuf;
 use std::env;
+use std::sync::{Mutex, OnceLock};
 use chrono::Utc;
 
+use crate::config;
+use crate::error::Error;
+use crate::provider::Provider;
+use crate::net::{self, RequestExt};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthInfo {
     #[serde(rename = "type")]
@@ -12,40 +18,272 @@ pub struct AuthInfo {
     pub refresh: Option<String>,
     pub access: Option<String>,
     pub expires: Option<i64>,
+    /// When to fetch a new Copilot token ahead of `expires`, in milliseconds,
+    /// from the `refresh_in` the token endpoint returns.
+    pub refresh_at: Option<i64>,
+    /// When the GitHub token was last confirmed valid, in milliseconds.
+    pub validated_at: Option<i64>,
+    /// Copilot API base URL from the token endpoint's `endpoints.api`.
+    pub api: Option<String>,
     pub key: Option<String>,
     pub token: Option<String>,
 }
 
-pub fn get_config_path() -> PathBuf {
-    let config_dir = env::var("XDG_CONFIG_HOME")
-        .unwrap_or_else(|_| format!("{}/.config", env::var("HOME").unwrap_or_else(|_| "/home/user".to_string())));
-    PathBuf::from(config_dir).join("008").join("auth.json")
+/// Environment variable that relocates th's whole config directory.
+pub const CONFIG_DIR_ENV: &str = "TH_CONFIG_DIR";
+
+/// Where th keeps its config, credentials and history: `TH_CONFIG_DIR` if
+/// set, otherwise `008` under `XDG_CONFIG_HOME` or `~/.config`.
+pub fn config_dir() -> Result<PathBuf, String> {
+    if let Some(dir) = env::var_os(CONFIG_DIR_ENV).filter(|d| !d.is_empty()) {
+        return Ok(PathBuf::from(dir));
+    }
+    if let Some(xdg) = env::var_os("XDG_CONFIG_HOME").filter(|d| !d.is_empty()) {
+        return Ok(PathBuf::from(xdg).join("008"));
+    }
+    let home = env::home_dir()
+        .filter(|home| !home.as_os_str().is_empty())
+        .ok_or_else(|| format!("Could not determine your home directory; set {} or XDG_CONFIG_HOME", CONFIG_DIR_ENV))?;
+    Ok(home.join(".config").join("008"))
+}
+
+/// `message` with a pointer to the variables that move the config directory.
+pub fn writable_dir_hint(message: String) -> String {
+    format!("{}. Set {} or XDG_CONFIG_HOME to a writable directory.", message, CONFIG_DIR_ENV)
 }
 
-pub async fn get_auth_info(provider: &str) -> Option<AuthInfo> {
-    let path = get_config_path();
-    if let Ok(contents) = fs::read_to_string(&path) {
-        if let Ok(data) = serde_json::from_str::<serde_json::Value>(&contents) {
-            if let Some(info) = data.get(provider) {
-                return serde_json::from_value(info.clone()).ok();
+/// `config_dir`, as an `Error` for when there is nowhere to keep files.
+pub fn get_config_dir() -> Result<PathBuf, Error> {
+    Ok(config_dir()?)
+}
+
+pub fn get_config_path() -> Result<PathBuf, Error> {
+    Ok(get_config_dir()?.join("auth.json"))
+}
+
+/// Where credentials are kept: `auth.json` and, when the config asks for
+/// it, the OS keyring for the secrets. The free functions below use the
+/// user's own store from `user`; `new` points one at another file, such as
+/// in tests.
+#[derive(Debug, Clone)]
+pub struct AuthStore {
+    path: PathBuf,
+    keyring: bool,
+}
+
+impl AuthStore {
+    /// The user's own store in the config directory, keeping secrets in the
+    /// OS keyring when `credential_store` says so. Resolved once per run; a
+    /// config that fails to load is an error rather than a quiet switch to
+    /// plaintext.
+    pub fn user() -> Result<Self, Error> {
+        if let Some(store) = USER_STORE.get() {
+            return Ok(store.clone());
+        }
+        let store = Self::new(get_config_path()?, config::load()?.credential_store() == "keyring");
+        Ok(USER_STORE.get_or_init(|| store).clone())
+    }
+
+    pub fn new(path: PathBuf, keyring: bool) -> Self {
+        Self { path, keyring }
+    }
+
+    /// The stored credentials for `provider`, or `None` if there are none. A
+    /// malformed `auth.json` is an error rather than a silent logout.
+    pub async fn get(&self, provider: &str) -> Result<Option<AuthInfo>, Error> {
+        let path = self.path.clone();
+        let Ok(contents) = fs::read_to_string(&path) else {
+            return Ok(None);
+        };
+        let Some(info) = parse_auth_file(&contents).map_err(|e| corrupt_auth_file(&path, &e))?.remove(provider) else {
+            return Ok(None);
+        };
+        let mut info: AuthInfo = serde_json::from_value(info).map_err(|e| corrupt_auth_file(&path, &e.to_string()))?;
+        if self.keyring {
+            info.refresh = read_secret(provider, "refresh").or(info.refresh);
+            info.access = read_secret(provider, "access").or(info.access);
+            info.key = read_secret(provider, "key").or(info.key);
+        }
+        Ok(Some(info))
+    }
+
+    pub async fn set(&self, provider: &str, info: AuthInfo) -> Result<(), Error> {
+        let path = self.path.clone();
+        let mut info = info;
+        if self.keyring {
+            match write_secret(provider, "refresh", info.refresh.as_deref())
+                .and_then(|_| write_secret(provider, "access", info.access.as_deref()))
+                .and_then(|_| write_secret(provider, "key", info.key.as_deref()))
+            {
+                Ok(()) => {
+                    info.refresh = None;
+                    info.access = None;
+                    info.key = None;
+                }
+                Err(e) => {
+                    // `get` prefers the keyring, so don't leave a mix of new and
+                    // stale secrets there when the file gets the fresh ones
+                    for field in ["refresh", "access", "key"] {
+                        let _ = write_secret(provider, field, None);
+                    }
+                    eprintln!("Warning: keyring unavailable ({}); storing tokens in {}", e, path.display())
+                }
             }
         }
+        let dir = path
+            .parent()
+            .ok_or_else(|| writable_dir_hint(format!("Invalid config path {}", path.display())))?;
+        #[cfg(unix)]
+        let created = !dir.exists();
+        fs::create_dir_all(dir)
+            .map_err(|e| writable_dir_hint(format!("Could not create config directory {}: {}", dir.display(), e)))?;
+        // Keep the tokens readable by the owner only, but leave a directory
+        // the user already had (possibly shared via TH_CONFIG_DIR) alone
+        #[cfg(unix)]
+        if created {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+                .map_err(|e| writable_dir_hint(format!("Could not restrict {}: {}", dir.display(), e)))?;
+        }
+        let mut data = match fs::read_to_string(&path) {
+            Ok(contents) => parse_auth_file(&contents).unwrap_or_else(|e| {
+                // Keep the unreadable file around rather than dropping the other
+                // providers' credentials without a trace
+                let backup = path.with_extension("json.bak");
+                match fs::copy(&path, &backup) {
+                    Ok(_) => eprintln!(
+                        "Warning: {} was malformed ({}); saved a copy to {}",
+                        path.display(),
+                        e,
+                        backup.display()
+                    ),
+                    Err(copy_error) => eprintln!(
+                        "Warning: {} was malformed ({}) and could not be backed up: {}",
+                        path.display(),
+                        e,
+                        copy_error
+                    ),
+                }
+                serde_json::Map::new()
+            }),
+            Err(_) => serde_json::Map::new(),
+        };
+        data.insert(provider.to_string(), serde_json::to_value(&info)?);
+        fs::write(&path, serde_json::to_string_pretty(&data)?)
+            .map_err(|e| writable_dir_hint(format!("Could not write {}: {}", path.display(), e)))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(())
+    }
+
+    /// Remove the stored credentials for `provider`, deleting `auth.json` if
+    /// nothing else is left in it. Returns whether there was anything to remove.
+    pub async fn clear(&self, provider: &str) -> Result<bool, Error> {
+        let path = self.path.clone();
+        let mut removed = false;
+        if self.keyring {
+            removed = ["refresh", "access", "key"].iter().any(|field| read_secret(provider, field).is_some());
+            // An unreachable keyring mustn't stop the file from being cleared
+            for field in ["refresh", "access", "key"] {
+                if let Err(e) = write_secret(provider, field, None) {
+                    net::log(format!("could not remove the {} {} from the keyring: {}", provider, field, e));
+                }
+            }
+        }
+        if !path.exists() {
+            return Ok(removed);
+        }
+        let mut data: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path)?)
+            .map_err(|e| format!("Could not parse {}: {}", path.display(), e))?;
+        let Some(object) = data.as_object_mut() else {
+            return Ok(removed);
+        };
+        removed |= object.remove(provider).is_some();
+        if object.is_empty() {
+            fs::remove_file(&path).map_err(|e| format!("Could not remove {}: {}", path.display(), e))?;
+        } else {
+            fs::write(&path, serde_json::to_string_pretty(&data)?)
+                .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+        }
+        Ok(removed)
     }
-    None
 }
 
-pub async fn set_auth_info(provider: &str, info: AuthInfo) -> Result<(), Box<dyn std::error::Error>> {
-    let path = get_config_path();
-    fs::create_dir_all(path.parent().unwrap())?;
-    let mut data: serde_json::Value = if path.exists() {
-        serde_json::from_str(&fs::read_to_string(&path)?).unwrap_or_default()
-    } else {
-        serde_json::Value::Object(serde_json::Map::new())
-    };
-    data[provider] = serde_json::to_value(&info)?;
-    fs::write(&path, serde_json::to_string_pretty(&data)?)?;
-    // Set permissions to 600, but in Rust, fs::set_permissions not directly, skip for now
-    Ok(())
+/// The stored credentials for `provider`; see `AuthStore::get`.
+pub async fn get_auth_info(provider: &str) -> Result<Option<AuthInfo>, Error> {
+    AuthStore::user()?.get(provider).await
+}
+
+pub async fn set_auth_info(provider: &str, info: AuthInfo) -> Result<(), Error> {
+    AuthStore::user()?.set(provider, info).await
+}
+
+/// Remove the stored credentials for `provider`; see `AuthStore::clear`.
+pub async fn clear_auth_info(provider: &str) -> Result<bool, Error> {
+    AuthStore::user()?.clear(provider).await
+}
+
+fn parse_auth_file(contents: &str) -> Result<serde_json::Map<String, serde_json::Value>, String> {
+    match serde_json::from_str(contents).map_err(|e| e.to_string())? {
+        serde_json::Value::Object(data) => Ok(data),
+        _ => Err("expected a JSON object".to_string()),
+    }
+}
+
+fn corrupt_auth_file(path: &std::path::Path, error: &str) -> Error {
+    Error::Parse(format!(
+        "Could not parse {}: {}. Fix or delete the file, then run `th login` again.",
+        path.display(),
+        error
+    ))
+}
+
+/// Key under which the Copilot credentials are stored.
+pub const PROVIDER: &str = "github-copilot";
+
+/// Used when the token response doesn't say which API host to talk to.
+const DEFAULT_COPILOT_API: &str = "https://api.githubcopilot.com";
+
+/// Environment variables that can hold the GitHub token, checked in order,
+/// for CI and containers where writing `auth.json` is awkward.
+pub const REFRESH_TOKEN_ENV: [&str; 2] = ["TH_REFRESH_TOKEN", "GH_COPILOT_TOKEN"];
+
+/// The GitHub token from the environment and the variable it came from.
+fn env_refresh_token() -> Option<(&'static str, String)> {
+    REFRESH_TOKEN_ENV
+        .iter()
+        .find_map(|name| env::var(name).ok().filter(|t| !t.trim().is_empty()).map(|t| (*name, t.trim().to_string())))
+}
+
+/// The API base named by a Copilot token minted from an environment token,
+/// which is never written to `auth.json`.
+static ENV_COPILOT_API: Mutex<Option<String>> = Mutex::new(None);
+
+const KEYRING_SERVICE: &str = "th";
+
+/// The store behind the free functions, from the first `AuthStore::user`.
+static USER_STORE: OnceLock<AuthStore> = OnceLock::new();
+
+fn keyring_entry(provider: &str, field: &str) -> keyring::Result<keyring::Entry> {
+    keyring::Entry::new(KEYRING_SERVICE, &format!("{}:{}", provider, field))
+}
+
+fn read_secret(provider: &str, field: &str) -> Option<String> {
+    keyring_entry(provider, field).ok()?.get_password().ok()
+}
+
+fn write_secret(provider: &str, field: &str, value: Option<&str>) -> keyring::Result<()> {
+    let entry = keyring_entry(provider, field)?;
+    match value {
+        Some(value) => entry.set_password(value),
+        None => match entry.delete_credential() {
+            Err(keyring::Error::NoEntry) => Ok(()),
+            result => result,
+        },
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -69,10 +307,14 @@ struct AccessTokenResponse {
 struct CopilotTokenResponse {
     token: String,
     expires_at: i64,
-    #[allow(dead_code)]
     refresh_in: i64,
-    #[allow(dead_code)]
-    endpoints: serde_json::Value,
+    #[serde(default)]
+    endpoints: CopilotEndpoints,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct CopilotEndpoints {
+    api: Option<String>,
 }
 
 #[derive(Debug)]
@@ -81,7 +323,6 @@ pub struct DeviceAuth {
     pub user_code: String,
     pub verification_uri: String,
     pub interval: i32,
-    #[allow(dead_code)]
     pub expires_in: i32,
 }
 
@@ -93,130 +334,571 @@ pub enum PollResult {
     SlowDown,
 }
 
-pub async fn authorize() -> Result<DeviceAuth, Box<dyn std::error::Error>> {
-    let client = Client::new();
-    let response = client
-        .post("https://github.com/login/device/code")
-        .header("Accept", "application/json")
-        .header("Content-Type", "application/json")
-        .header("User-Agent", "GitHubCopilotChat/0.26.7")
-        .json(&serde_json::json!({
-            "client_id": "Iv1.b507a08c87ecfe98",
-            "scope": "read:user"
-        }))
-        .send()
-        .await?;
-
-    let data: DeviceCodeResponse = response.json().await?;
-    Ok(DeviceAuth {
-        device_code: data.device_code,
-        user_code: data.user_code,
-        verification_uri: data.verification_uri,
-        interval: data.interval,
-        expires_in: data.expires_in,
-    })
-}
-
-pub async fn poll(device_code: &str) -> Result<PollResult, Box<dyn std::error::Error>> {
-    let client = Client::new();
-    let response = client
-        .post("https://github.com/login/oauth/access_token")
-        .header("Accept", "application/json")
-        .header("Content-Type", "application/json")
-        .header("User-Agent", "GitHubCopilotChat/0.26.7")
-        .json(&serde_json::json!({
-            "client_id": "Iv1.b507a08c87ecfe98",
-            "device_code": device_code,
-            "grant_type": "urn:ietf:params:oauth:grant-type:device_code"
-        }))
-        .send()
-        .await?;
-
-    let data: AccessTokenResponse = response.json().await?;
-    if let Some(token) = data.access_token {
-        // Store the GitHub OAuth token
-        let info = AuthInfo {
+/// How long a successful GitHub token validation is trusted.
+const VALIDATION_INTERVAL_MS: i64 = 60 * 60 * 1000;
+
+/// Store an API key for a key-based provider.
+pub async fn set_api_key(provider: Provider, key: &str) -> Result<(), Error> {
+    let info = AuthInfo {
+        auth_type: "api".to_string(),
+        refresh: None,
+        access: None,
+        expires: None,
+        refresh_at: None,
+        validated_at: None,
+        api: None,
+        key: Some(key.to_string()),
+        token: None,
+    };
+    set_auth_info(provider.auth_key(), info).await
+}
+
+/// A token handed out earlier in this run, reused until `until` (in
+/// milliseconds) so one invocation doesn't re-read and re-validate it, along
+/// with the API base a Copilot token was issued for.
+struct CachedToken {
+    provider: Provider,
+    token: String,
+    until: Option<i64>,
+    api: Option<String>,
+}
+
+static TOKEN_CACHE: Mutex<Option<CachedToken>> = Mutex::new(None);
+
+/// The bearer token for `provider`: a Copilot API token, refreshed as
+/// needed, or the stored API key. `None` means the user has to log in. The
+/// result is kept for the rest of the run.
+pub async fn token(provider: Provider) -> Result<Option<String>, Error> {
+    let now = Utc::now().timestamp_millis();
+    if let Some(cached) = TOKEN_CACHE.lock().unwrap().as_ref()
+        && cached.provider == provider
+        && cached.until.is_none_or(|until| until > now)
+    {
+        return Ok(Some(cached.token.clone()));
+    }
+    let fetched = if provider.uses_api_key() {
+        get_auth_info(provider.auth_key())
+            .await?
+            .and_then(|info| info.key)
+            .filter(|key| !key.is_empty())
+            .map(|key| (key, None))
+    } else {
+        access().await?.map(|(token, until)| (token, Some(until)))
+    };
+    let api = match (&fetched, provider) {
+        (Some(_), Provider::Copilot) => Some(stored_copilot_api_base().await),
+        _ => None,
+    };
+    *TOKEN_CACHE.lock().unwrap() = fetched
+        .clone()
+        .map(|(token, until)| CachedToken { provider, token, until, api });
+    Ok(fetched.map(|(token, _)| token))
+}
+
+/// The Copilot API base URL the last token response pointed at, or the
+/// default host if it didn't name one. Comes from the token cache once
+/// `token` has run, so chat requests don't re-read the credentials.
+pub async fn copilot_api_base() -> String {
+    let cached = TOKEN_CACHE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .filter(|cached| cached.provider == Provider::Copilot)
+        .and_then(|cached| cached.api.clone());
+    match cached {
+        Some(api) => api,
+        None => stored_copilot_api_base().await,
+    }
+}
+
+async fn stored_copilot_api_base() -> String {
+    if env_refresh_token().is_some() {
+        return ENV_COPILOT_API.lock().unwrap().clone().unwrap_or_else(|| DEFAULT_COPILOT_API.to_string());
+    }
+    get_auth_info(PROVIDER)
+        .await
+        .ok()
+        .flatten()
+        .and_then(|info| info.api)
+        .map(|api| api.trim_end_matches('/').to_string())
+        .unwrap_or_else(|| DEFAULT_COPILOT_API.to_string())
+}
+
+/// Forget the Copilot API token, in memory and on disk, so the next
+/// `token` call mints a new one. The GitHub token is kept.
+pub async fn invalidate_access() -> Result<(), Error> {
+    *TOKEN_CACHE.lock().unwrap() = None;
+    if env_refresh_token().is_some() {
+        return Ok(());
+    }
+    let Some(mut info) = get_auth_info(PROVIDER).await? else {
+        return Ok(());
+    };
+    net::log("auth: discarding rejected Copilot token");
+    info.access = None;
+    info.expires = None;
+    info.refresh_at = None;
+    set_auth_info(PROVIDER, info).await
+}
+
+/// Default hosts for the device-code sign-in and the GitHub API.
+pub const GITHUB_URL: &str = "https://github.com";
+pub const GITHUB_API_URL: &str = "https://api.github.com";
+
+/// The client, base URLs and credential store used to sign in to GitHub and
+/// exchange the GitHub token for Copilot tokens. The free functions use the
+/// hosts from the `[endpoints]` config, which default to the public ones,
+/// and the user's own store; building one with `new` points the flow at
+/// another server and store, such as a local mock and a temporary file.
+pub struct GithubAuth {
+    client: Client,
+    github_url: String,
+    api_url: String,
+    store: AuthStore,
+    /// GitHub token from `REFRESH_TOKEN_ENV` and the variable it came from.
+    env_token: Option<(&'static str, String)>,
+}
+
+impl GithubAuth {
+    /// The flow against the configured hosts, storing into the user's own
+    /// store and honouring a GitHub token from the environment.
+    pub fn user() -> Result<Self, Error> {
+        let config = config::load()?;
+        let auth = Self::new(Client::new(), config.github_url(), config.github_api_url(), AuthStore::user()?);
+        Ok(auth.with_env_token(env_refresh_token()))
+    }
+
+    /// A flow that only uses `store`; see `with_env_token` for the
+    /// environment override.
+    pub fn new(client: Client, github_url: &str, api_url: &str, store: AuthStore) -> Self {
+        Self {
+            client,
+            github_url: github_url.trim_end_matches('/').to_string(),
+            api_url: api_url.trim_end_matches('/').to_string(),
+            store,
+            env_token: None,
+        }
+    }
+
+    /// Prefer `token`, named after the variable it was read from, over the
+    /// store when minting Copilot tokens.
+    pub fn with_env_token(mut self, token: Option<(&'static str, String)>) -> Self {
+        self.env_token = token;
+        self
+    }
+
+    pub async fn authorize(&self) -> Result<DeviceAuth, Error> {
+        let response = self
+            .client
+            .post(format!("{}/login/device/code", self.github_url))
+            .header("Accept", "application/json")
+            .header("Content-Type", "application/json")
+            .header("User-Agent", "GitHubCopilotChat/0.26.7")
+            .json(&serde_json::json!({
+                "client_id": "Iv1.b507a08c87ecfe98",
+                "scope": "read:user"
+            }))
+            .send_with_retry()
+            .await?;
+
+        let data: DeviceCodeResponse = response.json().await?;
+        Ok(DeviceAuth {
+            device_code: data.device_code,
+            user_code: data.user_code,
+            verification_uri: data.verification_uri,
+            interval: data.interval,
+            expires_in: data.expires_in,
+        })
+    }
+
+    pub async fn poll(&self, device_code: &str) -> Result<PollResult, Error> {
+        let response = self
+            .client
+            .post(format!("{}/login/oauth/access_token", self.github_url))
+            .header("Accept", "application/json")
+            .header("Content-Type", "application/json")
+            .header("User-Agent", "GitHubCopilotChat/0.26.7")
+            .json(&serde_json::json!({
+                "client_id": "Iv1.b507a08c87ecfe98",
+                "device_code": device_code,
+                "grant_type": "urn:ietf:params:oauth:grant-type:device_code"
+            }))
+            .send_with_retry()
+            .await?;
+
+        let data: AccessTokenResponse = response.json().await?;
+        if let Some(token) = data.access_token {
+            // Store the GitHub OAuth token
+            let info = AuthInfo {
+                auth_type: "oauth".to_string(),
+                refresh: Some(token),
+                access: None,
+                expires: None,
+                refresh_at: None,
+                validated_at: None,
+                api: None,
+                key: None,
+                token: None,
+            };
+            self.store.set(PROVIDER, info).await?;
+            Ok(PollResult::Complete)
+        } else if data.error.as_deref() == Some("authorization_pending") {
+            Ok(PollResult::Pending)
+        } else if data.error.as_deref() == Some("slow_down") {
+            Ok(PollResult::SlowDown)
+        } else {
+            let error_msg = data.error.unwrap_or_else(|| "unknown error".to_string());
+            Ok(PollResult::Failed(error_msg))
+        }
+    }
+
+    /// Whether GitHub accepts the token. Network failures are errors rather than
+    /// a rejection, so being offline doesn't look like being logged out.
+    async fn validate_github_token(&self, token: &str) -> Result<bool, Error> {
+        let response = self
+            .client
+            .get(format!("{}/user", self.api_url))
+            .header("Authorization", format!("Bearer {}", token))
+            .header("User-Agent", "GitHubCopilotChat/0.26.7")
+            .send_with_retry()
+            .await?;
+        Ok(response.status().is_success())
+    }
+
+    /// A usable Copilot API token, refreshing it when due, together with when
+    /// it should next be refreshed in milliseconds. `None` means the user has to
+    /// log in. Unlike `token`, this always consults the stored credentials.
+    /// A GitHub token in one of `REFRESH_TOKEN_ENV` takes precedence: the
+    /// Copilot token is then minted in memory and nothing is written to disk.
+    pub async fn access(&self) -> Result<Option<(String, i64)>, Error> {
+        if let Some((name, refresh)) = &self.env_token {
+            net::log(format!("auth: using the GitHub token from {}", name));
+            let Some(token_data) = self.request_copilot_token(refresh).await? else {
+                return Err(Error::NotAuthenticated(format!("The GitHub token in {} was not accepted for Copilot.", name)));
+            };
+            *ENV_COPILOT_API.lock().unwrap() =
+                token_data.endpoints.api.filter(|api| !api.is_empty()).map(|api| api.trim_end_matches('/').to_string());
+            let refresh_at = Utc::now().timestamp_millis() + token_data.refresh_in * 1000;
+            return Ok(Some((token_data.token, refresh_at)));
+        }
+
+        let info = match self.store.get(PROVIDER).await? {
+            Some(i) if i.auth_type == "oauth" => i,
+            _ => return Ok(None),
+        };
+
+        let refresh = info
+            .refresh
+            .as_ref()
+            .ok_or_else(|| Error::NotAuthenticated("No GitHub token stored. Please run 'th login' first.".to_string()))?;
+
+        let now = Utc::now().timestamp_millis();
+        let current = match (&info.access, info.expires) {
+            (Some(access), Some(expires)) if expires > now => Some((access.clone(), expires)),
+            _ => None,
+        };
+        if let Some((access, expires)) = &current
+            && info.refresh_at.is_none_or(|at| at > now)
+        {
+            net::log("auth: using cached Copilot token");
+            return Ok(Some((access.clone(), info.refresh_at.unwrap_or(*expires).min(*expires))));
+        }
+
+        // Validate the GitHub token, unless that was done recently
+        let validated_at = match info.validated_at {
+            Some(at) if now - at < VALIDATION_INTERVAL_MS => {
+                net::log("auth: GitHub token validated recently, skipping check");
+                at
+            }
+            _ => match self.validate_github_token(refresh).await {
+                Ok(true) => {
+                    net::log("auth: GitHub token validated");
+                    now
+                }
+                // Token invalid, need re-auth once the current one runs out
+                Ok(false) => {
+                    net::log("auth: GitHub token rejected");
+                    return Ok(current);
+                }
+                Err(_) if current.is_some() => {
+                    net::log("auth: couldn't validate GitHub token, using cached Copilot token");
+                    return Ok(current);
+                }
+                Err(e) => return Err(e),
+            },
+        };
+
+        // Refresh early, but keep using a still-valid token if that fails
+        net::log("auth: refreshing Copilot token");
+        match self.fetch_copilot_token(refresh, validated_at).await {
+            Ok(None) | Err(_) if current.is_some() => {
+                net::log("auth: refresh failed, using cached Copilot token");
+                Ok(current)
+            }
+            result => result,
+        }
+    }
+
+    /// Exchange the GitHub token for a new Copilot API token and store it,
+    /// returning the token and when to refresh it.
+    async fn fetch_copilot_token(&self, refresh: &str, validated_at: i64) -> Result<Option<(String, i64)>, Error> {
+        let Some(token_data) = self.request_copilot_token(refresh).await? else {
+            return Ok(None);
+        };
+
+        // Store the Copilot API token
+        let refresh_at = Utc::now().timestamp_millis() + token_data.refresh_in * 1000;
+        let new_info = AuthInfo {
             auth_type: "oauth".to_string(),
-            refresh: Some(token),
-            access: None,
-            expires: None,
+            refresh: Some(refresh.to_string()),
+            access: Some(token_data.token.clone()),
+            expires: Some(token_data.expires_at * 1000),
+            refresh_at: Some(refresh_at),
+            validated_at: Some(validated_at),
+            api: token_data.endpoints.api.filter(|api| !api.is_empty()),
             key: None,
             token: None,
         };
-        set_auth_info("github-copilot", info).await?;
-        Ok(PollResult::Complete)
-    } else if data.error.as_deref() == Some("authorization_pending") {
-        Ok(PollResult::Pending)
-    } else if data.error.as_deref() == Some("slow_down") {
-        Ok(PollResult::SlowDown)
-    } else {
-        let error_msg = data.error.unwrap_or_else(|| "unknown error".to_string());
-        Ok(PollResult::Failed(error_msg))
+        self.store.set(PROVIDER, new_info).await?;
+
+        Ok(Some((token_data.token, refresh_at)))
     }
-}
 
-async fn validate_github_token(token: &str) -> bool {
-    let client = Client::new();
-    let response = client
-        .get("https://api.github.com/user")
-        .header("Authorization", format!("Bearer {}", token))
-        .header("User-Agent", "GitHubCopilotChat/0.26.7")
-        .send()
-        .await;
+    /// Exchange the GitHub token for a Copilot API token without storing it.
+    /// `None` means GitHub refused the exchange.
+    async fn request_copilot_token(&self, refresh: &str) -> Result<Option<CopilotTokenResponse>, Error> {
+        let response = self
+            .client
+            .get(format!("{}/copilot_internal/v2/token", self.api_url))
+            .header("Accept", "application/json")
+            .header("Authorization", format!("Bearer {}", refresh))
+            .header("User-Agent", "GitHubCopilotChat/0.26.7")
+            .header("Editor-Version", "vscode/1.99.3")
+            .header("Editor-Plugin-Version", "copilot-chat/0.26.7")
+            .send_with_retry()
+            .await?;
 
-    match response {
-        Ok(resp) => resp.status().is_success(),
-        Err(_) => false,
+        if !response.status().is_success() {
+            return Ok(None);
+        }
+        Ok(Some(response.json().await?))
     }
 }
 
-pub async fn access() -> Result<Option<String>, Box<dyn std::error::Error>> {
-    let info = match get_auth_info("github-copilot").await {
-        Some(i) if i.auth_type == "oauth" => i,
-        _ => return Ok(None),
-    };
+pub async fn authorize() -> Result<DeviceAuth, Error> {
+    GithubAuth::user()?.authorize().await
+}
+
+pub async fn poll(device_code: &str) -> Result<PollResult, Error> {
+    GithubAuth::user()?.poll(device_code).await
+}
+
+/// A usable Copilot API token and when to refresh it; see `GithubAuth::access`.
+pub async fn access() -> Result<Option<(String, i64)>, Error> {
+    GithubAuth::user()?.access().await
+}
 
-    let refresh = info.refresh.as_ref().ok_or("No refresh token")?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
 
-    // Validate the GitHub token
-    if !validate_github_token(refresh).await {
-        return Ok(None); // Token invalid, need re-auth
+    /// A `GithubAuth` talking to `server` and storing credentials in a
+    /// temporary directory, which is returned so it outlives the test.
+    fn mock_auth(server: &MockServer) -> (GithubAuth, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = AuthStore::new(dir.path().join("auth.json"), false);
+        (GithubAuth::new(Client::new(), &server.uri(), &server.uri(), store), dir)
     }
 
-    if let (Some(access), Some(expires)) = (&info.access, &info.expires) {
-        if *expires > Utc::now().timestamp_millis() {
-            return Ok(Some(access.clone()));
+    fn oauth_info(refresh: &str) -> AuthInfo {
+        AuthInfo {
+            auth_type: "oauth".to_string(),
+            refresh: Some(refresh.to_string()),
+            access: None,
+            expires: None,
+            refresh_at: None,
+            validated_at: None,
+            api: None,
+            key: None,
+            token: None,
         }
     }
 
-    // Get new Copilot API token
-    let client = Client::new();
-    let response = client
-        .get("https://api.github.com/copilot_internal/v2/token")
-        .header("Accept", "application/json")
-        .header("Authorization", format!("Bearer {}", refresh))
-        .header("User-Agent", "GitHubCopilotChat/0.26.7")
-        .header("Editor-Version", "vscode/1.99.3")
-        .header("Editor-Plugin-Version", "copilot-chat/0.26.7")
-        .send()
-        .await?;
+    async fn poll_with(body: serde_json::Value) -> (PollResult, Option<AuthInfo>) {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/login/oauth/access_token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(body))
+            .mount(&server)
+            .await;
+        let (auth, _dir) = mock_auth(&server);
+        let result = auth.poll("device").await.unwrap();
+        (result, auth.store.get(PROVIDER).await.unwrap())
+    }
 
-    if !response.status().is_success() {
-        return Ok(None);
+    #[tokio::test]
+    async fn clear_removes_only_that_provider() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = AuthStore::new(dir.path().join("auth.json"), false);
+        store.set(PROVIDER, oauth_info("gho_a")).await.unwrap();
+        store.set("openai", oauth_info("sk")).await.unwrap();
+        assert!(store.clear(PROVIDER).await.unwrap());
+        assert!(store.get(PROVIDER).await.unwrap().is_none());
+        assert!(store.get("openai").await.unwrap().is_some());
+        assert!(!store.clear(PROVIDER).await.unwrap());
+        assert!(store.clear("openai").await.unwrap());
+        assert!(!dir.path().join("auth.json").exists());
     }
 
-    let token_data: CopilotTokenResponse = response.json().await?;
+    #[tokio::test]
+    async fn authorize_returns_the_device_code() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/login/device/code"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "device_code": "dev",
+                "user_code": "ABCD-1234",
+                "verification_uri": "https://github.com/login/device",
+                "expires_in": 900,
+                "interval": 5
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let (auth, _dir) = mock_auth(&server);
+        let device = auth.authorize().await.unwrap();
+        assert_eq!(device.device_code, "dev");
+        assert_eq!(device.user_code, "ABCD-1234");
+        assert_eq!(device.interval, 5);
+        assert_eq!(device.expires_in, 900);
+    }
 
-    // Store the Copilot API token
-    let new_info = AuthInfo {
-        auth_type: "oauth".to_string(),
-        refresh: Some(refresh.clone()),
-        access: Some(token_data.token.clone()),
-        expires: Some(token_data.expires_at * 1000),
-        key: None,
-        token: None,
-    };
-    set_auth_info("github-copilot", new_info).await?;
+    #[tokio::test]
+    async fn poll_reports_pending() {
+        let (result, stored) = poll_with(serde_json::json!({ "error": "authorization_pending" })).await;
+        assert!(matches!(result, PollResult::Pending));
+        assert!(stored.is_none());
+    }
 
-    Ok(Some(token_data.token))
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn poll_reports_slow_down() {
+        let (result, stored) = poll_with(serde_json::json!({ "error": "slow_down" })).await;
+        assert!(matches!(result, PollResult::SlowDown));
+        assert!(stored.is_none());
+    }
+
+    #[tokio::test]
+    async fn poll_stores_the_token_on_completion() {
+        let (result, stored) = poll_with(serde_json::json!({ "access_token": "gho_token" })).await;
+        assert!(matches!(result, PollResult::Complete));
+        let stored = stored.unwrap();
+        assert_eq!(stored.auth_type, "oauth");
+        assert_eq!(stored.refresh.as_deref(), Some("gho_token"));
+    }
+
+    #[tokio::test]
+    async fn poll_reports_other_errors_as_failures() {
+        let (result, stored) = poll_with(serde_json::json!({ "error": "expired_token" })).await;
+        assert!(matches!(result, PollResult::Failed(ref e) if e == "expired_token"));
+        assert!(stored.is_none());
+    }
+
+    #[tokio::test]
+    async fn access_refreshes_an_expired_copilot_token() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/user"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "login": "octocat" })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/copilot_internal/v2/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "token": "copilot-new",
+                "expires_at": Utc::now().timestamp() + 1800,
+                "refresh_in": 1500,
+                "endpoints": { "api": "https://copilot.example.com" }
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let (auth, _dir) = mock_auth(&server);
+        let expired = AuthInfo {
+            access: Some("copilot-old".to_string()),
+            expires: Some(Utc::now().timestamp_millis() - 1000),
+            ..oauth_info("gho_token")
+        };
+        auth.store.set(PROVIDER, expired).await.unwrap();
+
+        let (token, refresh_at) = auth.access().await.unwrap().unwrap();
+        assert_eq!(token, "copilot-new");
+        assert!(refresh_at > Utc::now().timestamp_millis());
+        let stored = auth.store.get(PROVIDER).await.unwrap().unwrap();
+        assert_eq!(stored.access.as_deref(), Some("copilot-new"));
+        assert_eq!(stored.refresh.as_deref(), Some("gho_token"));
+        assert_eq!(stored.api.as_deref(), Some("https://copilot.example.com"));
+        assert!(stored.validated_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn access_reuses_a_fresh_copilot_token() {
+        let server = MockServer::start().await;
+        Mock::given(wiremock::matchers::any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(0)
+            .mount(&server)
+            .await;
+        let (auth, _dir) = mock_auth(&server);
+        let now = Utc::now().timestamp_millis();
+        let fresh = AuthInfo {
+            access: Some("copilot-cached".to_string()),
+            expires: Some(now + 600_000),
+            refresh_at: Some(now + 300_000),
+            ..oauth_info("gho_token")
+        };
+        auth.store.set(PROVIDER, fresh).await.unwrap();
+
+        let (token, refresh_at) = auth.access().await.unwrap().unwrap();
+        assert_eq!(token, "copilot-cached");
+        assert_eq!(refresh_at, now + 300_000);
+    }
+
+    #[tokio::test]
+    async fn access_needs_a_login_when_github_rejects_the_token() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/user"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+        let (auth, _dir) = mock_auth(&server);
+        auth.store.set(PROVIDER, oauth_info("gho_revoked")).await.unwrap();
+
+        assert!(auth.access().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn access_prefers_the_env_token_without_touching_the_store() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/copilot_internal/v2/token"))
+            .and(wiremock::matchers::header("authorization", "Bearer ghu_env"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "token": "copilot-env",
+                "expires_at": Utc::now().timestamp() + 1800,
+                "refresh_in": 1500
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let (auth, _dir) = mock_auth(&server);
+        let auth = auth.with_env_token(Some(("TH_REFRESH_TOKEN", "ghu_env".to_string())));
+
+        let (token, _) = auth.access().await.unwrap().unwrap();
+        assert_eq!(token, "copilot-env");
+        assert!(auth.store.get(PROVIDER).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,44 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub const DEFAULT_MAX_RETRIES: u32 = 10;
+pub const DEFAULT_MAX_RETRY_SECS: u64 = 120;
+
+/// A process-wide allowance for retries, shared by every retry loop so that
+/// compounding retries (auth, token refresh, chat) can't spiral into dozens
+/// of requests in a single run.
+struct Budget {
+    max_retries: u32,
+    max_delay: Duration,
+    retries: u32,
+    delay: Duration,
+}
+
+static BUDGET: Mutex<Budget> = Mutex::new(Budget {
+    max_retries: DEFAULT_MAX_RETRIES,
+    max_delay: Duration::from_secs(DEFAULT_MAX_RETRY_SECS),
+    retries: 0,
+    delay: Duration::ZERO,
+});
+
+pub fn configure(max_retries: u32, max_delay: Duration) {
+    let mut budget = BUDGET.lock().unwrap();
+    budget.max_retries = max_retries;
+    budget.max_delay = max_delay;
+}
+
+/// Claim one retry that will wait `delay` first. Fails once either the retry
+/// count or the total backoff time would exceed the budget.
+pub fn spend(delay: Duration) -> Result<(), String> {
+    let mut budget = BUDGET.lock().unwrap();
+    if budget.retries >= budget.max_retries || budget.delay + delay > budget.max_delay {
+        return Err(format!(
+            "Giving up after {} retries ({}s of backoff); raise retry_budget or retry_budget_secs in the config to allow more.",
+            budget.retries,
+            budget.delay.as_secs()
+        ));
+    }
+    budget.retries += 1;
+    budget.delay += delay;
+    Ok(())
+}
@@ -0,0 +1,534 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::auth;
+use crate::budget;
+use crate::error::Error;
+use crate::provider::Provider;
+
+pub const DEFAULT_MAX_COMMAND_LENGTH: usize = 1000;
+pub const DEFAULT_TEMPERATURE: f64 = 0.2;
+pub const DEFAULT_MAX_TOKENS: u32 = 180;
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_APPROVAL_TIMEOUT_SECS: u64 = 60;
+pub const CREDENTIAL_STORES: [&str; 2] = ["file", "keyring"];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub model: Option<String>,
+    /// Ring the terminal bell (or run `sound_command`) when a command finishes.
+    pub bell_on_complete: Option<bool>,
+    pub sound_command: Option<String>,
+    /// Ask why a proposal was rejected and record the answer in the history log.
+    pub prompt_rejection_reason: Option<bool>,
+    /// Seconds to wait for the device-code login; defaults to the code's lifetime.
+    pub login_timeout: Option<u64>,
+    /// Commands longer than this need an explicit "yes" to run.
+    pub max_command_length: Option<usize>,
+    /// Where tokens are kept: "file" (auth.json) or "keyring" (OS keyring,
+    /// falling back to the file when no keyring is available).
+    pub credential_store: Option<String>,
+    /// Most retries a single run may make across all requests.
+    pub retry_budget: Option<u32>,
+    /// Most total seconds a single run may spend backing off between retries.
+    pub retry_budget_secs: Option<u64>,
+    /// Steer proposals towards a trash tool instead of `rm` when one is installed.
+    pub prefer_trash: Option<bool>,
+    /// Syntax-highlight proposed commands, as with `--highlight`.
+    pub highlight: Option<bool>,
+    /// Sampling temperature for planning requests.
+    pub temperature: Option<f64>,
+    /// Shell used to run approved commands.
+    pub shell: Option<String>,
+    /// Run proposals without asking, as with `--yes`.
+    pub auto_approve: Option<bool>,
+    /// Extra substrings that mark a command as dangerous, on top of the
+    /// built-in checks. Set as a comma-separated list.
+    pub dangerous_patterns: Option<Vec<String>>,
+    /// Command prefixes that run without asking, such as `ls` or
+    /// `git status`. Set as a comma-separated list.
+    pub trusted_commands: Option<Vec<String>>,
+    /// Seconds to wait for a model response; 0 waits indefinitely.
+    pub timeout: Option<u64>,
+    /// Chat API to plan with: "copilot", "openai" or "anthropic".
+    pub provider: Option<String>,
+    /// Seconds to wait for an answer at the approval prompt before treating
+    /// it as "no"; 0 waits indefinitely.
+    pub approval_timeout: Option<u64>,
+    /// Token cap for a planning response; raise it if long commands come
+    /// back truncated.
+    pub max_tokens: Option<u32>,
+    /// Progress spinner appearance, under `[spinner]`.
+    pub spinner: Option<SpinnerConfig>,
+    /// Hosts to use instead of the public ones, under `[endpoints]`.
+    pub endpoints: Option<EndpointsConfig>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpinnerConfig {
+    /// Animation frames, one per character, or "ascii" for a plain set.
+    pub frames: Option<String>,
+    /// Milliseconds each frame is shown.
+    pub interval_ms: Option<u64>,
+}
+
+/// Base URLs for proxies and GitHub Enterprise. Each can also be set with the
+/// environment variable next to it in `ENDPOINT_ENV_VARS`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EndpointsConfig {
+    /// Chat API base; `/chat/completions` is appended. Overrides the
+    /// provider's own host, including the one Copilot's token names.
+    pub api: Option<String>,
+    /// GitHub host for the device-code sign-in.
+    pub github: Option<String>,
+    /// GitHub API host that validates the GitHub token and issues Copilot
+    /// tokens.
+    pub github_api: Option<String>,
+}
+
+/// Environment variables that override the `[endpoints]` keys, beating the
+/// config files but not command-line flags.
+pub const ENDPOINT_ENV_VARS: [(&str, &str); 3] = [
+    ("endpoints.api", "TH_API_BASE"),
+    ("endpoints.github", "TH_GITHUB_URL"),
+    ("endpoints.github_api", "TH_GITHUB_API_URL"),
+];
+
+/// Every key accepted by `Config::get` and `Config::set`.
+pub const KEYS: [&str; 25] = [
+    "model",
+    "bell_on_complete",
+    "sound_command",
+    "prompt_rejection_reason",
+    "login_timeout",
+    "max_command_length",
+    "credential_store",
+    "retry_budget",
+    "retry_budget_secs",
+    "prefer_trash",
+    "highlight",
+    "temperature",
+    "shell",
+    "auto_approve",
+    "dangerous_patterns",
+    "trusted_commands",
+    "timeout",
+    "provider",
+    "approval_timeout",
+    "max_tokens",
+    "spinner.frames",
+    "spinner.interval_ms",
+    "endpoints.api",
+    "endpoints.github",
+    "endpoints.github_api",
+];
+
+impl Config {
+    pub fn model(&self) -> &str {
+        self.model.as_deref().unwrap_or_else(|| self.provider().default_model())
+    }
+
+    pub fn bell_on_complete(&self) -> bool {
+        self.bell_on_complete.unwrap_or(false)
+    }
+
+    pub fn prompt_rejection_reason(&self) -> bool {
+        self.prompt_rejection_reason.unwrap_or(false)
+    }
+
+    pub fn max_command_length(&self) -> usize {
+        self.max_command_length.unwrap_or(DEFAULT_MAX_COMMAND_LENGTH)
+    }
+
+    pub fn credential_store(&self) -> &str {
+        self.credential_store.as_deref().unwrap_or("file")
+    }
+
+    pub fn retry_budget(&self) -> u32 {
+        self.retry_budget.unwrap_or(budget::DEFAULT_MAX_RETRIES)
+    }
+
+    pub fn retry_budget_secs(&self) -> u64 {
+        self.retry_budget_secs.unwrap_or(budget::DEFAULT_MAX_RETRY_SECS)
+    }
+
+    pub fn prefer_trash(&self) -> bool {
+        self.prefer_trash.unwrap_or(false)
+    }
+
+    pub fn highlight(&self) -> bool {
+        self.highlight.unwrap_or(false)
+    }
+
+    pub fn temperature(&self) -> f64 {
+        self.temperature.unwrap_or(DEFAULT_TEMPERATURE)
+    }
+
+    pub fn max_tokens(&self) -> u32 {
+        self.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS)
+    }
+
+    pub fn auto_approve(&self) -> bool {
+        self.auto_approve.unwrap_or(false)
+    }
+
+    /// How long to wait for a model response; `None` waits indefinitely.
+    pub fn timeout(&self) -> Option<std::time::Duration> {
+        match self.timeout.unwrap_or(DEFAULT_TIMEOUT_SECS) {
+            0 => None,
+            secs => Some(std::time::Duration::from_secs(secs)),
+        }
+    }
+
+    /// How long the approval prompt waits; `None` waits indefinitely.
+    pub fn approval_timeout(&self) -> Option<std::time::Duration> {
+        match self.approval_timeout.unwrap_or(DEFAULT_APPROVAL_TIMEOUT_SECS) {
+            0 => None,
+            secs => Some(std::time::Duration::from_secs(secs)),
+        }
+    }
+
+    /// The configured provider; `set` rejects unknown names, so a bad value
+    /// can only come from hand-editing and falls back to Copilot.
+    pub fn provider(&self) -> Provider {
+        self.provider.as_deref().and_then(|p| Provider::parse(p).ok()).unwrap_or_default()
+    }
+
+    /// The chat API base URL override, if any.
+    pub fn api_base(&self) -> Option<&str> {
+        self.endpoints.as_ref().and_then(|e| e.api.as_deref())
+    }
+
+    pub fn github_url(&self) -> &str {
+        self.endpoints.as_ref().and_then(|e| e.github.as_deref()).unwrap_or(auth::GITHUB_URL)
+    }
+
+    pub fn github_api_url(&self) -> &str {
+        self.endpoints.as_ref().and_then(|e| e.github_api.as_deref()).unwrap_or(auth::GITHUB_API_URL)
+    }
+
+    pub fn dangerous_patterns(&self) -> &[String] {
+        self.dangerous_patterns.as_deref().unwrap_or(&[])
+    }
+
+    pub fn trusted_commands(&self) -> &[String] {
+        self.trusted_commands.as_deref().unwrap_or(&[])
+    }
+
+    /// The effective value of a config key, with defaults applied. `None`
+    /// means the key is unset and has no default.
+    pub fn get(&self, key: &str) -> Result<Option<String>, String> {
+        let value = match key {
+            "model" => Some(self.model().to_string()),
+            "bell_on_complete" => Some(self.bell_on_complete().to_string()),
+            "sound_command" => self.sound_command.clone(),
+            "prompt_rejection_reason" => Some(self.prompt_rejection_reason().to_string()),
+            "login_timeout" => self.login_timeout.map(|v| v.to_string()),
+            "max_command_length" => Some(self.max_command_length().to_string()),
+            "credential_store" => Some(self.credential_store().to_string()),
+            "retry_budget" => Some(self.retry_budget().to_string()),
+            "retry_budget_secs" => Some(self.retry_budget_secs().to_string()),
+            "prefer_trash" => Some(self.prefer_trash().to_string()),
+            "highlight" => Some(self.highlight().to_string()),
+            "temperature" => Some(self.temperature().to_string()),
+            "shell" => self.shell.clone(),
+            "auto_approve" => Some(self.auto_approve().to_string()),
+            "dangerous_patterns" => self.dangerous_patterns.as_ref().map(|p| p.join(",")),
+            "trusted_commands" => self.trusted_commands.as_ref().map(|p| p.join(",")),
+            "timeout" => Some(self.timeout.unwrap_or(DEFAULT_TIMEOUT_SECS).to_string()),
+            "provider" => Some(self.provider().name().to_string()),
+            "approval_timeout" => Some(self.approval_timeout.unwrap_or(DEFAULT_APPROVAL_TIMEOUT_SECS).to_string()),
+            "max_tokens" => Some(self.max_tokens().to_string()),
+            "spinner.frames" => self.spinner.as_ref().and_then(|s| s.frames.clone()),
+            "spinner.interval_ms" => self.spinner.as_ref().and_then(|s| s.interval_ms).map(|v| v.to_string()),
+            "endpoints.api" => self.api_base().map(str::to_string),
+            "endpoints.github" => Some(self.github_url().to_string()),
+            "endpoints.github_api" => Some(self.github_api_url().to_string()),
+            _ => return Err(format!("Unknown config key: {}", key)),
+        };
+        Ok(value)
+    }
+
+    /// Set a config key from its string form. `None` resets it to the default.
+    pub fn set(&mut self, key: &str, value: Option<&str>) -> Result<(), String> {
+        match key {
+            "model" => self.model = value.map(str::to_string),
+            "bell_on_complete" => self.bell_on_complete = parse_optional(key, value)?,
+            "sound_command" => self.sound_command = value.map(str::to_string),
+            "prompt_rejection_reason" => self.prompt_rejection_reason = parse_optional(key, value)?,
+            "login_timeout" => self.login_timeout = parse_optional(key, value)?,
+            "max_command_length" => self.max_command_length = parse_optional(key, value)?,
+            "retry_budget" => self.retry_budget = parse_optional(key, value)?,
+            "retry_budget_secs" => self.retry_budget_secs = parse_optional(key, value)?,
+            "prefer_trash" => self.prefer_trash = parse_optional(key, value)?,
+            "highlight" => self.highlight = parse_optional(key, value)?,
+            "temperature" => {
+                let temperature = parse_optional(key, value)?;
+                if let Some(t) = temperature
+                    && !(0.0..=2.0).contains(&t)
+                {
+                    return Err(format!("temperature must be between 0.0 and 2.0, got {}", t));
+                }
+                self.temperature = temperature;
+            }
+            "spinner.frames" | "spinner.interval_ms" => {
+                let spinner = self.spinner.get_or_insert_with(SpinnerConfig::default);
+                if key == "spinner.frames" {
+                    if value.is_some_and(|v| v.trim().is_empty()) {
+                        return Err("spinner.frames needs at least one character".to_string());
+                    }
+                    spinner.frames = value.map(str::to_string);
+                } else {
+                    let interval_ms = parse_optional(key, value)?;
+                    if interval_ms == Some(0) {
+                        return Err("spinner.interval_ms must be at least 1".to_string());
+                    }
+                    spinner.interval_ms = interval_ms;
+                }
+                if spinner.frames.is_none() && spinner.interval_ms.is_none() {
+                    self.spinner = None;
+                }
+            }
+            "endpoints.api" | "endpoints.github" | "endpoints.github_api" => {
+                if let Some(v) = value
+                    && !(v.starts_with("https://") || v.starts_with("http://"))
+                {
+                    return Err(format!("{} must be an http:// or https:// URL, got {}", key, v));
+                }
+                let url = value.map(|v| v.trim_end_matches('/').to_string());
+                let endpoints = self.endpoints.get_or_insert_with(EndpointsConfig::default);
+                match key {
+                    "endpoints.api" => endpoints.api = url,
+                    "endpoints.github" => endpoints.github = url,
+                    _ => endpoints.github_api = url,
+                }
+                if endpoints.api.is_none() && endpoints.github.is_none() && endpoints.github_api.is_none() {
+                    self.endpoints = None;
+                }
+            }
+            "max_tokens" => {
+                let max_tokens = parse_optional(key, value)?;
+                if max_tokens == Some(0) {
+                    return Err("max_tokens must be at least 1".to_string());
+                }
+                self.max_tokens = max_tokens;
+            }
+            "shell" => self.shell = value.map(str::to_string),
+            "auto_approve" => self.auto_approve = parse_optional(key, value)?,
+            "timeout" => self.timeout = parse_optional(key, value)?,
+            "approval_timeout" => self.approval_timeout = parse_optional(key, value)?,
+            "dangerous_patterns" => {
+                self.dangerous_patterns = value.map(|v| {
+                    v.split(',').map(str::trim).filter(|p| !p.is_empty()).map(str::to_string).collect()
+                })
+            }
+            "trusted_commands" => {
+                self.trusted_commands = value.map(|v| {
+                    v.split(',').map(str::trim).filter(|p| !p.is_empty()).map(str::to_string).collect()
+                })
+            }
+            "provider" => {
+                if let Some(v) = value {
+                    Provider::parse(v)?;
+                }
+                self.provider = value.map(str::to_lowercase);
+            }
+            "credential_store" => {
+                if let Some(v) = value
+                    && !CREDENTIAL_STORES.contains(&v)
+                {
+                    return Err(format!("credential_store must be one of: {}", CREDENTIAL_STORES.join(", ")));
+                }
+                self.credential_store = value.map(str::to_string);
+            }
+            _ => return Err(format!("Unknown config key: {}", key)),
+        }
+        Ok(())
+    }
+}
+
+fn parse_optional<T: std::str::FromStr>(key: &str, value: Option<&str>) -> Result<Option<T>, String> {
+    value
+        .map(|v| v.parse().map_err(|_| format!("Invalid value for {}: {}", key, v)))
+        .transpose()
+}
+
+/// Environment variable selecting an extra `config.<env>.toml` layer.
+pub const ENV_VAR: &str = "TH_ENV";
+
+pub fn get_config_file_path() -> Result<PathBuf, Error> {
+    Ok(auth::get_config_dir()?.join("config.toml"))
+}
+
+/// Path of the `config.<env>.toml` layer selected by `TH_ENV`, if any.
+pub fn get_env_config_file_path() -> Result<Option<PathBuf>, Error> {
+    let Some(env) = env::var(ENV_VAR).ok().filter(|e| !e.trim().is_empty()) else {
+        return Ok(None);
+    };
+    Ok(Some(auth::get_config_dir()?.join(format!("config.{}.toml", env.trim()))))
+}
+
+/// Load the effective config. Precedence, lowest to highest: built-in
+/// defaults, `config.toml`, `config.<env>.toml` when `TH_ENV` is set, then
+/// the `ENDPOINT_ENV_VARS`.
+/// Command-line flags are applied on top with `Resolved::apply_flag`.
+/// Tables are merged key by key, so a layer only needs the keys it changes.
+/// Missing files are skipped.
+pub fn load() -> Result<Config, Error> {
+    Ok(load_resolved()?.config)
+}
+
+/// The effective config together with where each key's value came from.
+pub struct Resolved {
+    pub config: Config,
+    sources: BTreeMap<String, String>,
+}
+
+impl Resolved {
+    pub fn source(&self, key: &str) -> &str {
+        self.sources.get(key).map(String::as_str).unwrap_or("default")
+    }
+
+    /// Run every loaded value through the checks `Config::set` applies, so a
+    /// file can't hold what `th config set` would refuse.
+    fn validate(&self) -> Result<(), String> {
+        // `get` reports the provider in effect, which hides an unknown name
+        if let Some(provider) = &self.config.provider {
+            Provider::parse(provider).map_err(|e| format!("Invalid config: {} (from {})", e, self.source("provider")))?;
+        }
+        let mut check = Config::default();
+        for key in KEYS {
+            if let Some(value) = self.config.get(key)? {
+                check.set(key, Some(&value)).map_err(|e| format!("Invalid config: {} (from {})", e, self.source(key)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Override a key from a command-line flag, which beats every file layer.
+    pub fn apply_flag(&mut self, key: &str, value: &str) -> Result<(), String> {
+        self.config.set(key, Some(value))?;
+        self.sources.insert(key.to_string(), "flag".to_string());
+        Ok(())
+    }
+}
+
+/// Like `load`, but also records which layer set each key.
+pub fn load_resolved() -> Result<Resolved, Error> {
+    let mut sources = BTreeMap::new();
+    let base_path = get_config_file_path()?;
+    let mut merged = read_table(&base_path)?;
+    record_sources(&mut sources, &merged, &base_path);
+    if let Some(env_path) = get_env_config_file_path()? {
+        let layer = read_table(&env_path)?;
+        record_sources(&mut sources, &layer, &env_path);
+        merge_tables(&mut merged, layer);
+    }
+    let mut resolved = Resolved { config: toml::Value::Table(merged).try_into()?, sources };
+    resolved.validate()?;
+    for (key, var) in ENDPOINT_ENV_VARS {
+        if let Some(value) = env::var(var).ok().filter(|v| !v.trim().is_empty()) {
+            resolved.config.set(key, Some(value.trim())).map_err(|e| format!("{} (from {})", e, var))?;
+            resolved.sources.insert(key.to_string(), format!("${}", var));
+        }
+    }
+    Ok(resolved)
+}
+
+/// Load only the file that `save` writes to: the `TH_ENV` layer when one is
+/// selected, otherwise `config.toml`.
+pub fn load_writable() -> Result<Config, Error> {
+    Ok(toml::Value::Table(read_table(&get_writable_config_path()?)?).try_into()?)
+}
+
+pub fn get_writable_config_path() -> Result<PathBuf, Error> {
+    match get_env_config_file_path()? {
+        Some(path) => Ok(path),
+        None => get_config_file_path(),
+    }
+}
+
+/// Note `path` as the source of every key in `table`, naming keys in nested
+/// tables `table.key`.
+fn record_sources(sources: &mut BTreeMap<String, String>, table: &toml::Table, path: &Path) {
+    for (key, value) in table {
+        match value {
+            toml::Value::Table(nested) => {
+                for nested_key in nested.keys() {
+                    sources.insert(format!("{}.{}", key, nested_key), path.display().to_string());
+                }
+            }
+            _ => {
+                sources.insert(key.clone(), path.display().to_string());
+            }
+        }
+    }
+}
+
+fn read_table(path: &Path) -> Result<toml::Table, Error> {
+    if !path.exists() {
+        return Ok(toml::Table::new());
+    }
+    let contents = fs::read_to_string(path)?;
+    toml::from_str(&contents).map_err(|e| format!("Invalid config file {}: {}", path.display(), e).into())
+}
+
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(overlay_table)) => {
+                merge_tables(base_table, overlay_table);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+pub fn save(config: &Config) -> Result<(), Error> {
+    let path = get_writable_config_path()?;
+    let dir = path
+        .parent()
+        .ok_or_else(|| auth::writable_dir_hint(format!("Invalid config path {}", path.display())))?;
+    fs::create_dir_all(dir)
+        .map_err(|e| auth::writable_dir_hint(format!("Could not create config directory {}: {}", dir.display(), e)))?;
+    fs::write(&path, toml::to_string_pretty(config)?)
+        .map_err(|e| auth::writable_dir_hint(format!("Could not write {}: {}", path.display(), e)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolved(toml: &str) -> Resolved {
+        let config: Config = toml::from_str(toml).unwrap();
+        let sources = BTreeMap::from([("temperature".to_string(), "config.toml".to_string())]);
+        Resolved { config, sources }
+    }
+
+    #[test]
+    fn validate_accepts_defaults_and_sane_values() {
+        assert!(resolved("").validate().is_ok());
+        assert!(resolved("temperature = 0.7\nmax_tokens = 500\n").validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_out_of_range_values() {
+        let error = resolved("temperature = 9.5\n").validate().unwrap_err();
+        assert!(error.contains("temperature must be between 0.0 and 2.0"), "{}", error);
+        assert!(error.contains("config.toml"), "{}", error);
+        assert!(resolved("max_tokens = 0\n").validate().is_err());
+        assert!(resolved("[spinner]\ninterval_ms = 0\n").validate().is_err());
+        assert!(resolved("[endpoints]\napi = \"ftp://example.com\"\n").validate().is_err());
+        let error = resolved("provider = \"copilto\"\n").validate().unwrap_err();
+        assert!(error.contains("provider must be one of"), "{}", error);
+    }
+}
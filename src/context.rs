@@ -0,0 +1,364 @@
+use clap::ValueEnum;
+use std::io::{self, IsTerminal, Read};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Largest `git diff` included in the context, in bytes.
+const MAX_DIFF_BYTES: usize = 8000;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum DiffScope {
+    /// Changes staged for commit
+    Staged,
+    /// Changes not yet staged
+    Unstaged,
+    /// Both staged and unstaged changes
+    All,
+}
+
+/// Run git with `args` and return its stdout, or `None` if git is missing or fails.
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+pub fn inside_git_repo() -> bool {
+    git(&["rev-parse", "--is-inside-work-tree"]).is_some_and(|out| out.trim() == "true")
+}
+
+/// The operating system, with the distribution ID on Linux (e.g.
+/// `linux (arch)`), so commands can target the right package manager.
+pub fn platform() -> String {
+    let os = std::env::consts::OS;
+    if os != "linux" {
+        return os.to_string();
+    }
+    let distro = std::fs::read_to_string("/etc/os-release").ok().and_then(|release| {
+        release
+            .lines()
+            .find_map(|line| line.strip_prefix("ID="))
+            .map(|id| id.trim().trim_matches('"').to_string())
+            .filter(|id| !id.is_empty())
+    });
+    match distro {
+        Some(distro) => format!("linux ({})", distro),
+        None => "linux".to_string(),
+    }
+}
+
+/// Default for how many directory entries are listed in the context.
+pub const MAX_LISTED_FILES: usize = 50;
+
+/// Largest directory listing included in the context, in bytes.
+const MAX_LISTING_BYTES: usize = 2000;
+
+/// Task words too common to say anything about which files matter.
+const STOP_WORDS: [&str; 10] = ["the", "and", "all", "for", "with", "from", "into", "that", "this", "files"];
+
+/// Names in the current directory with a trailing `/` on directories, at most
+/// `max` of them, most relevant to `task` first. Dotfiles are skipped and long
+/// listings are cut off with a count of what was left out.
+pub fn list_files(task: &str, max: usize) -> Option<String> {
+    let names: Vec<String> = std::fs::read_dir(".")
+        .ok()?
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                return None;
+            }
+            let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+            Some(if is_dir { format!("{}/", name) } else { name })
+        })
+        .collect();
+    rank_files(names, task, max)
+}
+
+/// Order `names` by how many of the task's words they match, alphabetically
+/// within a score, and keep the first `max` that fit the byte budget.
+fn rank_files(names: Vec<String>, task: &str, max: usize) -> Option<String> {
+    if names.is_empty() {
+        return None;
+    }
+    let keywords = words(task);
+    let mut ranked: Vec<(usize, String)> = names
+        .into_iter()
+        .map(|name| {
+            let name_words = words(&name);
+            let score = keywords.iter().filter(|k| name_words.iter().any(|w| w.starts_with(k.as_str()))).count();
+            (score, name)
+        })
+        .collect();
+    ranked.sort_by(|(a_score, a), (b_score, b)| b_score.cmp(a_score).then_with(|| a.cmp(b)));
+    let total = ranked.len();
+    let mut listing = String::new();
+    let mut listed = 0;
+    for (_, name) in ranked.iter().take(max) {
+        if !listing.is_empty() && listing.len() + name.len() + 1 > MAX_LISTING_BYTES {
+            break;
+        }
+        if !listing.is_empty() {
+            listing.push('\n');
+        }
+        listing.push_str(name);
+        listed += 1;
+    }
+    if listed == 0 {
+        return None;
+    }
+    if total > listed {
+        listing.push_str(&format!("\n({} more)", total - listed));
+    }
+    Some(listing)
+}
+
+/// The distinct lowercase words of `text` worth matching file names against.
+fn words(text: &str) -> Vec<String> {
+    let mut words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.len() >= 3)
+        .map(str::to_lowercase)
+        .filter(|w| !STOP_WORDS.contains(&w.as_str()))
+        .collect();
+    words.sort();
+    words.dedup();
+    words
+}
+
+/// Names of the files that describe a project's conventions to th, in order
+/// of preference within a directory.
+const PROJECT_FILES: [&str; 2] = [".th.md", ".thcontext"];
+
+/// Largest project context file included in the context, in bytes.
+const MAX_PROJECT_FILE_BYTES: usize = 4000;
+
+/// The nearest project context file and its contents, truncated to a fixed
+/// budget. Looks in the current directory and its parents up to the root of
+/// the enclosing git repository; outside a repository only the current
+/// directory is checked. Missing and unreadable files are skipped.
+pub fn project_file() -> Option<(PathBuf, String)> {
+    let cwd = std::env::current_dir().ok()?;
+    let root = git(&["rev-parse", "--show-toplevel"]).map(|out| PathBuf::from(out.trim()));
+    for dir in cwd.ancestors() {
+        for name in PROJECT_FILES {
+            let path = dir.join(name);
+            if let Ok(text) = std::fs::read_to_string(&path)
+                && !text.trim().is_empty()
+            {
+                return Some((path, truncate_bytes(text.trim_end(), MAX_PROJECT_FILE_BYTES)));
+            }
+        }
+        if root.as_deref().is_none_or(|root| dir == root) {
+            break;
+        }
+    }
+    None
+}
+
+/// Most `git status` lines listed in the context before summarising the rest.
+const MAX_STATUS_LINES: usize = 10;
+
+/// Branch, working tree status and remote of the enclosing repository, as
+/// context lines. `None` outside a git repository or without git.
+pub fn git_summary() -> Option<String> {
+    if !inside_git_repo() {
+        return None;
+    }
+    let mut summary = String::new();
+    let branch = git(&["branch", "--show-current"]).unwrap_or_default();
+    let branch = branch.trim();
+    summary.push_str(&format!("git branch: {}\n", if branch.is_empty() { "(detached HEAD)" } else { branch }));
+
+    let status = git(&["status", "--porcelain"]).unwrap_or_default();
+    let lines: Vec<&str> = status.lines().collect();
+    if lines.is_empty() {
+        summary.push_str("git status: clean\n");
+    } else {
+        summary.push_str(&format!("git status: {} changed paths\n", lines.len()));
+        for line in lines.iter().take(MAX_STATUS_LINES) {
+            summary.push_str(&format!("  {}\n", line));
+        }
+        if lines.len() > MAX_STATUS_LINES {
+            summary.push_str(&format!("  ({} more)\n", lines.len() - MAX_STATUS_LINES));
+        }
+    }
+
+    let remote = git(&["remote", "get-url", "origin"])
+        .or_else(|| {
+            let first = git(&["remote"])?.lines().next()?.to_string();
+            git(&["remote", "get-url", &first])
+        });
+    if let Some(remote) = remote {
+        summary.push_str(&format!("git remote: {}\n", strip_credentials(remote.trim())));
+    }
+    Some(summary.trim_end().to_string())
+}
+
+/// Drop any `user:token@` part of a remote URL so credentials aren't sent.
+fn strip_credentials(url: &str) -> String {
+    match url.split_once("://") {
+        Some((scheme, rest)) => match rest.split_once('@') {
+            Some((userinfo, host)) if !userinfo.contains('/') => format!("{}://{}", scheme, host),
+            _ => url.to_string(),
+        },
+        None => url.to_string(),
+    }
+}
+
+/// The working tree diff for `scope`, truncated to a fixed budget. `None`
+/// outside a git repository or when there are no changes.
+pub fn git_diff(scope: DiffScope) -> Option<String> {
+    if !inside_git_repo() {
+        return None;
+    }
+    let staged = || git(&["diff", "--cached"]).unwrap_or_default();
+    let unstaged = || git(&["diff"]).unwrap_or_default();
+    let diff = match scope {
+        DiffScope::Staged => staged(),
+        DiffScope::Unstaged => unstaged(),
+        DiffScope::All => {
+            let (staged, unstaged) = (staged(), unstaged());
+            let mut diff = String::new();
+            if !staged.trim().is_empty() {
+                diff.push_str("# staged\n");
+                diff.push_str(&staged);
+            }
+            if !unstaged.trim().is_empty() {
+                diff.push_str("# unstaged\n");
+                diff.push_str(&unstaged);
+            }
+            diff
+        }
+    };
+    if diff.trim().is_empty() {
+        return None;
+    }
+    Some(truncate_bytes(&diff, MAX_DIFF_BYTES))
+}
+
+/// Read whatever was piped to stdin, keeping at most `max` bytes. `None`
+/// when stdin is a terminal or the input is blank.
+pub fn piped_input(max: usize) -> Option<String> {
+    let stdin = io::stdin();
+    if stdin.is_terminal() {
+        return None;
+    }
+    let mut bytes = Vec::new();
+    stdin.lock().take(max as u64 + 1).read_to_end(&mut bytes).ok()?;
+    let truncated = bytes.len() > max;
+    let text = String::from_utf8_lossy(&bytes[..bytes.len().min(max)]).into_owned();
+    if text.trim().is_empty() {
+        return None;
+    }
+    Some(if truncated { format!("{}\n… (input truncated at {} bytes)", text, max) } else { text })
+}
+
+/// How long a `--context-command` may run before it is killed.
+const CONTEXT_COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest output kept from a `--context-command`, in bytes.
+const MAX_CONTEXT_COMMAND_BYTES: usize = 4000;
+
+/// Run `command` with `sh -c` and return its stdout, truncated to a fixed
+/// budget. The command is killed if it runs too long; that, a failure to
+/// start and a non-zero exit are all errors describing what happened.
+pub fn command_output(command: &str) -> Result<String, String> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("could not start: {}", e))?;
+    // Drain stdout on a thread so a chatty command can't block on a full pipe
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let reader = thread::spawn(move || {
+        let mut bytes = Vec::new();
+        let _ = stdout.read_to_end(&mut bytes);
+        bytes
+    });
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            break status;
+        }
+        if started.elapsed() > CONTEXT_COMMAND_TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("timed out after {}s", CONTEXT_COMMAND_TIMEOUT.as_secs()));
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    let bytes = reader.join().unwrap_or_default();
+    if !status.success() {
+        return Err(match status.code() {
+            Some(code) => format!("exited with status {}", code),
+            None => "was killed by a signal".to_string(),
+        });
+    }
+    let output = String::from_utf8_lossy(&bytes);
+    Ok(truncate_bytes(output.trim_end(), MAX_CONTEXT_COMMAND_BYTES))
+}
+
+/// Cut `text` to at most `max` bytes on a character boundary, noting how much
+/// was dropped.
+pub fn truncate_bytes(text: &str, max: usize) -> String {
+    if text.len() <= max {
+        return text.to_string();
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}\n… ({} more bytes truncated)", &text[..end], text.len() - end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn ranks_names_matching_the_task_first() {
+        let files = names(&["Cargo.toml", "Dockerfile", "README.md", "logs/", "src/"]);
+        let listing = rank_files(files, "build the docker image and rotate logs", 50).unwrap();
+        assert_eq!(listing, "Dockerfile\nlogs/\nCargo.toml\nREADME.md\nsrc/");
+    }
+
+    #[test]
+    fn ignores_stop_words_and_short_words() {
+        let files = names(&["other.txt", "theme.css", "a.out", "index.html"]);
+        let listing = rank_files(files, "find all the files in a dir", 50).unwrap();
+        assert_eq!(listing, "a.out\nindex.html\nother.txt\ntheme.css");
+    }
+
+    #[test]
+    fn keeps_only_the_most_relevant() {
+        let files = names(&["a.txt", "b.txt", "report.pdf", "c.txt"]);
+        let listing = rank_files(files, "open the report", 2).unwrap();
+        assert_eq!(listing, "report.pdf\na.txt\n(2 more)");
+    }
+
+    #[test]
+    fn stops_at_the_byte_budget() {
+        let files: Vec<String> = (0..500).map(|i| format!("file-{:04}.txt", i)).collect();
+        let listing = rank_files(files, "", 500).unwrap();
+        assert!(listing.len() <= MAX_LISTING_BYTES + "\n(500 more)".len());
+        assert!(listing.starts_with("file-0000.txt\n"));
+        assert!(listing.ends_with(" more)"));
+    }
+
+    #[test]
+    fn empty_directories_list_nothing() {
+        assert_eq!(rank_files(Vec::new(), "anything", 50), None);
+    }
+}
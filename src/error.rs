@@ -0,0 +1,123 @@
+use reqwest::StatusCode;
+
+use crate::net;
+
+/// Everything that can stop a run, sorted by what went wrong so callers can
+/// react to specific failures and `main` can pick a message and exit code.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// No usable credentials; the message says how to log in.
+    #[error("{0}")]
+    NotAuthenticated(String),
+    #[error(transparent)]
+    Unreachable(#[from] net::Unreachable),
+    #[error(transparent)]
+    Network(#[from] reqwest::Error),
+    /// The API answered with an error, either as a failed response or as an
+    /// error event inside the stream (where there is no status).
+    #[error("{}", describe_api_error(*status, *retry_after, body))]
+    Api {
+        status: Option<StatusCode>,
+        retry_after: Option<u64>,
+        body: String,
+    },
+    /// The model answered with an empty command, meaning it found no safe
+    /// way to do the task; holds its explanation.
+    #[error("No safe command: {0}")]
+    NoSafeCommand(String),
+    /// A file or response that couldn't be understood.
+    #[error("{0}")]
+    Parse(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// The user stopped the run, e.g. with Ctrl-C.
+    #[error("{0}")]
+    Interrupted(String),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl Error {
+    /// The exit status for a run that ends with this error, following the
+    /// BSD `sysexits.h` conventions.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::NotAuthenticated(_) => 77,
+            Error::Unreachable(_) | Error::Network(_) => 69,
+            Error::Api { .. } => 76,
+            Error::NoSafeCommand(_) => 3,
+            Error::Parse(_) => 65,
+            Error::Io(_) => 74,
+            Error::Interrupted(_) => 130,
+            Error::Other(_) => 1,
+        }
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::Other(message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Error::Other(message.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Parse(error.to_string())
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(error: toml::de::Error) -> Self {
+        Error::Parse(error.to_string())
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(error: toml::ser::Error) -> Self {
+        Error::Other(error.to_string())
+    }
+}
+
+impl From<keyring::Error> for Error {
+    fn from(error: keyring::Error) -> Self {
+        Error::Other(format!("keyring: {}", error))
+    }
+}
+
+/// Turn a failed response into a readable message, calling out rate limits
+/// along with how long to wait when the API says so.
+fn describe_api_error(status: Option<StatusCode>, retry_after: Option<u64>, body: &str) -> String {
+    let parsed: Option<serde_json::Value> = serde_json::from_str(body).ok();
+    let error = parsed.as_ref().map(|value| value.get("error").unwrap_or(value));
+    let message = error
+        .and_then(|e| e.get("message").or_else(|| e.get("error_description")))
+        .and_then(|m| m.as_str())
+        .map(str::to_string)
+        .or_else(|| error.and_then(|e| e.as_str()).map(str::to_string))
+        .unwrap_or_else(|| body.trim().to_string());
+    let code = error
+        .and_then(|e| e.get("code").or_else(|| e.get("type")))
+        .and_then(|c| c.as_str())
+        .unwrap_or("");
+
+    let rate_limited = status == Some(StatusCode::TOO_MANY_REQUESTS)
+        || code.contains("rate")
+        || message.to_lowercase().contains("rate limit");
+    if rate_limited {
+        let wait = match retry_after {
+            Some(secs) => format!("try again in {} seconds", secs),
+            None => "try again shortly".to_string(),
+        };
+        return format!("Rate limited: {} ({})", wait, message);
+    }
+    match status {
+        Some(status) => format!("API request failed: {} {}", status, message),
+        None => format!("API request failed: {}", message),
+    }
+}
@@ -0,0 +1,99 @@
+use std::env;
+
+/// What one word of a command expands to in the current directory.
+pub struct Expansion {
+    pub word: String,
+    /// Expanded values, at most the cap passed to `expansions`.
+    pub values: Vec<String>,
+    /// Matches left out because of the cap.
+    pub more: usize,
+    /// Why the word couldn't be expanded, if it couldn't.
+    pub note: Option<String>,
+}
+
+/// Expand the variables, `~` and globs in each word of `command` without
+/// running anything. Only words that would change are returned. Command
+/// substitutions are never evaluated; they are reported instead.
+pub fn expansions(command: &str, cap: usize) -> Vec<Expansion> {
+    let mut result = Vec::new();
+    for word in command.split_whitespace() {
+        // Nothing expands inside single quotes
+        if word.starts_with('\'') {
+            continue;
+        }
+        let quoted = word.starts_with('"');
+        let bare = word.trim_matches('"');
+        if bare.contains("$(") || bare.contains('`') {
+            result.push(Expansion {
+                word: word.to_string(),
+                values: Vec::new(),
+                more: 0,
+                note: Some("command substitution, not evaluated".to_string()),
+            });
+            continue;
+        }
+        let expanded = expand_variables(bare);
+        let globbed = !quoted && expanded.contains(['*', '?', '[']);
+        if expanded == bare && !globbed {
+            continue;
+        }
+        let mut expansion = Expansion { word: word.to_string(), values: Vec::new(), more: 0, note: None };
+        if !globbed {
+            expansion.values.push(expanded);
+        } else {
+            match glob::glob(&expanded) {
+                Ok(paths) => {
+                    for path in paths.flatten() {
+                        if expansion.values.len() < cap {
+                            expansion.values.push(path.display().to_string());
+                        } else {
+                            expansion.more += 1;
+                        }
+                    }
+                    if expansion.values.is_empty() {
+                        expansion.note = Some(format!("no matches; the shell passes `{}` as is", expanded));
+                    }
+                }
+                Err(e) => expansion.note = Some(format!("invalid pattern: {}", e)),
+            }
+        }
+        result.push(expansion);
+    }
+    result
+}
+
+/// Replace `$NAME`, `${NAME}` and a leading `~` with their values; unset
+/// variables expand to nothing, as in the shell.
+fn expand_variables(word: &str) -> String {
+    let mut out = String::new();
+    let mut rest = word;
+    if let Some(after) = rest.strip_prefix('~')
+        && (after.is_empty() || after.starts_with('/'))
+        && let Some(home) = env::home_dir()
+    {
+        out.push_str(&home.display().to_string());
+        rest = after;
+    }
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, remainder) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => ("", after),
+            },
+            None => {
+                let end = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            }
+        };
+        if name.is_empty() {
+            out.push('$');
+        } else {
+            out.push_str(&env::var(name).unwrap_or_default());
+        }
+        rest = remainder;
+    }
+    out.push_str(rest);
+    out
+}
@@ -0,0 +1,119 @@
+use colored::*;
+use std::io::{self, Write};
+use serde::{Deserialize, Serialize};
+use crate::error::Error;
+use crate::{config, extract_json, request_completion, stream_content, timed_out_message, within, Message, RequestOptions, Spinner};
+
+const EXPLAIN_PROMPT: &str = "You are a shell command explainer. Given a shell command, describe what it does step by step in plain text: what each part and flag does, and anything surprising or risky about running it. Be concise. Do not propose other commands and do not use Markdown formatting.";
+
+const EXPLAIN_JSON_PROMPT: &str = "You are a shell command explainer. Given a shell command, respond with ONLY a JSON object containing fields: \"summary\" (a one or two sentence description of what the command does), \"flags\" (an array of objects with \"flag\" and \"meaning\" for each option, argument or pipeline stage worth explaining), and \"risks\" (an array of short strings describing anything destructive or surprising; empty if none). Do not include any other text or formatting.";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StructuredExplanation {
+    summary: String,
+    #[serde(default)]
+    flags: Vec<FlagExplanation>,
+    #[serde(default)]
+    risks: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FlagExplanation {
+    flag: String,
+    meaning: String,
+}
+
+/// Ask the model to explain `command` without ever executing it. With
+/// `structured` the answer is parsed into fields and rendered as a table, and
+/// with `json` those fields are printed raw for other tools to consume.
+pub async fn run(
+    command: &str,
+    structured: bool,
+    json: bool,
+    label: Option<&str>,
+    config: &config::Config,
+) -> Result<(), Error> {
+    let structured = structured || json;
+    let system = if structured { EXPLAIN_JSON_PROMPT } else { EXPLAIN_PROMPT };
+    let messages = vec![
+        Message { role: "system".to_string(), content: system.to_string() },
+        Message { role: "user".to_string(), content: format!("Command: {}", command) },
+    ];
+    let options = RequestOptions { max_tokens: config.max_tokens().max(600), ..RequestOptions::new(config) };
+
+    let mut spinner = Spinner::new(label.unwrap_or("Explaining…").to_string());
+    // Plain explanations are printed as they stream in; structured ones have
+    // to be parsed whole first
+    let mut printed = 0;
+    let on_content = |content: &str| {
+        if structured {
+            return;
+        }
+        let text = content.trim_start();
+        if text.len() > printed && text.is_char_boundary(printed) {
+            spinner.stop();
+            print!("{}", &text[printed..]);
+            let _ = io::stdout().flush();
+            printed = text.len();
+        }
+    };
+    let response = within(options.timeout, request_completion(&messages, &options, on_content)).await;
+    spinner.stop();
+    if printed > 0 {
+        println!();
+    }
+
+    let content = match response {
+        Ok(Ok(Some(completion))) => {
+            if completion.interrupted {
+                eprintln!("{} the connection dropped mid-response; the explanation may be incomplete", "Warning:".yellow());
+            }
+            stream_content(&completion.body)
+        }
+        Ok(Ok(None)) => String::new(),
+        Ok(Err(e @ (Error::Unreachable(_) | Error::Api { .. }))) => return Err(e),
+        Ok(Err(e)) => return Err(format!("Failed to query API: {}", e).into()),
+        Err(_) => return Err(timed_out_message(&options).into()),
+    };
+    if content.trim().is_empty() {
+        return Err("No explanation returned.".into());
+    }
+
+    if !structured {
+        if printed == 0 {
+            println!("{}", content.trim());
+        }
+        return Ok(());
+    }
+
+    let explanation: StructuredExplanation = extract_json(&content)
+        .and_then(|value| serde_json::from_value(value).ok())
+        .ok_or("The model did not return a structured explanation.")?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&explanation)?);
+    } else {
+        render_structured(&explanation);
+    }
+    Ok(())
+}
+
+fn render_structured(explanation: &StructuredExplanation) {
+    println!("  {} {}", "summary:".blue(), explanation.summary);
+
+    if !explanation.flags.is_empty() {
+        println!();
+        let width = explanation.flags.iter().map(|f| f.flag.chars().count()).max().unwrap_or(0).max(4);
+        println!("  {}  {}", format!("{:<width$}", "flag").blue(), "meaning".blue());
+        for flag in &explanation.flags {
+            println!("  {}  {}", format!("{:<width$}", flag.flag).green(), flag.meaning.dimmed());
+        }
+    }
+
+    if !explanation.risks.is_empty() {
+        println!();
+        println!("  {}", "risks:".blue());
+        for risk in &explanation.risks {
+            println!("  {} {}", "-".red(), risk);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use colored::*;
+
+/// Colour a shell command for display: the command word of each statement,
+/// flags, quoted strings, variables, operators and comments each get their
+/// own style. Falls back to plain green when colours are unavailable.
+pub fn highlight(command: &str) -> String {
+    if !colored::control::SHOULD_COLORIZE.should_colorize() || std::env::var("TERM").is_ok_and(|t| t == "dumb") {
+        return command.green().to_string();
+    }
+
+    let mut out = String::new();
+    let mut chars = command.chars().peekable();
+    let mut expect_command = true;
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' | '\t' | '\n' => {
+                out.push(c);
+                chars.next();
+                if c == '\n' {
+                    expect_command = true;
+                }
+            }
+            '#' => {
+                let mut comment = String::new();
+                while let Some(&c) = chars.peek()
+                    && c != '\n'
+                {
+                    comment.push(c);
+                    chars.next();
+                }
+                out.push_str(&comment.dimmed().to_string());
+            }
+            '|' | '&' | ';' | '<' | '>' => {
+                let mut operator = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !matches!(c, '|' | '&' | ';' | '<' | '>') {
+                        break;
+                    }
+                    operator.push(c);
+                    chars.next();
+                }
+                expect_command = !operator.starts_with('<') && !operator.starts_with('>');
+                out.push_str(&operator.magenta().to_string());
+            }
+            '\'' | '"' => {
+                out.push_str(&read_quoted(&mut chars, c).yellow().to_string());
+                expect_command = false;
+            }
+            '$' => {
+                out.push_str(&read_word(&mut chars).cyan().to_string());
+                expect_command = false;
+            }
+            _ => {
+                let word = read_word(&mut chars);
+                // Leading VAR=value assignments keep us waiting for the command
+                let styled = if expect_command && !word.contains('=') {
+                    expect_command = matches!(word.as_str(), "sudo" | "env" | "time" | "xargs" | "nohup");
+                    word.green().bold()
+                } else if word.starts_with('-') {
+                    word.blue()
+                } else {
+                    word.normal()
+                };
+                out.push_str(&styled.to_string());
+            }
+        }
+    }
+    out
+}
+
+/// Read a quoted string including its quotes, honouring backslash escapes
+/// inside double quotes.
+fn read_quoted(chars: &mut std::iter::Peekable<std::str::Chars>, quote: char) -> String {
+    let mut text = String::new();
+    text.push(chars.next().unwrap());
+    while let Some(c) = chars.next() {
+        text.push(c);
+        if c == '\\' && quote == '"' {
+            if let Some(escaped) = chars.next() {
+                text.push(escaped);
+            }
+        } else if c == quote {
+            break;
+        }
+    }
+    text
+}
+
+/// Read up to the next whitespace or operator, keeping quoted sections and
+/// `$(...)`/`${...}` groups intact.
+fn read_word(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut word = String::new();
+    let mut depth = 0usize;
+    while let Some(&c) = chars.peek() {
+        if depth == 0 && (c.is_whitespace() || matches!(c, '|' | '&' | ';' | '<' | '>')) {
+            break;
+        }
+        match c {
+            '\'' | '"' => {
+                word.push_str(&read_quoted(chars, c));
+                continue;
+            }
+            '(' | '{' => depth += 1,
+            ')' | '}' => depth = depth.saturating_sub(1),
+            '\\' => {
+                word.push(c);
+                chars.next();
+                if let Some(escaped) = chars.next() {
+                    word.push(escaped);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        word.push(c);
+        chars.next();
+    }
+    word
+}
@@ -0,0 +1,228 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use colored::*;
+
+use crate::auth;
+use crate::error::Error;
+use crate::net;
+
+/// One line of the JSON-lines history log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub timestamp: DateTime<Utc>,
+    pub task: String,
+    pub command: String,
+    pub executed: bool,
+    /// Whether the executed command exited successfully.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub succeeded: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rejection_reason: Option<String>,
+    /// The `X-Request-Id` sent with the chat request that produced the command.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// How many identical entries this one stands for after `dedupe`.
+    #[serde(skip)]
+    pub repeats: usize,
+    /// Position in the history file counting from 1, as shown by
+    /// `th history` and accepted by `th run`.
+    #[serde(skip)]
+    pub number: usize,
+    /// Captured output of a failed command, kept only for `--iterate`.
+    #[serde(skip)]
+    pub output: Option<String>,
+    /// Exit code of a failed command, passed on as th's own exit code.
+    #[serde(skip)]
+    pub exit_code: Option<i32>,
+}
+
+impl HistoryEntry {
+    pub fn new(task: &str, command: &str, executed: bool) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            task: task.to_string(),
+            command: command.to_string(),
+            executed,
+            succeeded: None,
+            rejection_reason: None,
+            request_id: net::sent_request_id().map(str::to_string),
+            repeats: 1,
+            number: 0,
+            output: None,
+            exit_code: None,
+        }
+    }
+}
+
+pub fn get_history_path() -> Result<PathBuf, Error> {
+    Ok(auth::get_config_dir()?.join("history.jsonl"))
+}
+
+pub fn append(entry: &HistoryEntry) -> Result<(), Error> {
+    let path = get_history_path()?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    Ok(())
+}
+
+/// Delete the history log. Returns whether there was one.
+pub fn clear() -> Result<bool, Error> {
+    let path = get_history_path()?;
+    if !path.exists() {
+        return Ok(false);
+    }
+    fs::remove_file(&path)?;
+    Ok(true)
+}
+
+/// Read every well-formed entry, oldest first. A missing log is empty.
+pub fn load() -> Result<Vec<HistoryEntry>, Error> {
+    let mut entries = Vec::new();
+    for_each_entry(|entry| entries.push(entry))?;
+    Ok(entries)
+}
+
+fn for_each_entry(mut f: impl FnMut(HistoryEntry)) -> Result<(), Error> {
+    let path = get_history_path()?;
+    if !path.exists() {
+        return Ok(());
+    }
+    let reader = BufReader::new(fs::File::open(&path)?);
+    let mut number = 0;
+    for line in reader.lines() {
+        if let Ok(mut entry) = serde_json::from_str::<HistoryEntry>(&line?) {
+            number += 1;
+            entry.repeats = 1;
+            entry.number = number;
+            f(entry);
+        }
+    }
+    Ok(())
+}
+
+/// Collapse entries with the same task and command into the most recent one,
+/// keeping the order of their last occurrence.
+pub fn dedupe(entries: Vec<HistoryEntry>) -> Vec<HistoryEntry> {
+    let mut positions: HashMap<(String, String), usize> = HashMap::new();
+    let mut deduped: Vec<Option<HistoryEntry>> = Vec::new();
+    for mut entry in entries {
+        let key = (entry.task.clone(), entry.command.clone());
+        if let Some(previous) = positions.get(&key).and_then(|&i| deduped[i].take()) {
+            entry.repeats += previous.repeats;
+        }
+        positions.insert(key, deduped.len());
+        deduped.push(Some(entry));
+    }
+    deduped.into_iter().flatten().collect()
+}
+
+/// The entry numbered `number` in `th history`.
+pub fn entry(number: usize) -> Result<HistoryEntry, Error> {
+    let mut entries = load()?;
+    match entries.len() {
+        0 => Err("No history yet.".into()),
+        len if number == 0 || number > len => {
+            Err(format!("No history entry {}; entries are numbered 1 to {}.", number, len).into())
+        }
+        _ => Ok(entries.swap_remove(number - 1)),
+    }
+}
+
+pub fn print_entries(entries: &[HistoryEntry], limit: usize) {
+    if entries.is_empty() {
+        println!("No history yet.");
+        return;
+    }
+    let start = entries.len().saturating_sub(limit);
+    let width = entries.iter().map(|e| e.number).max().unwrap_or(0).to_string().len();
+    for entry in &entries[start..] {
+        let status = match (entry.executed, entry.succeeded) {
+            (false, _) => "rejected".yellow(),
+            (true, Some(false)) => "failed".red(),
+            (true, _) => "ran".green(),
+        };
+        let repeats = if entry.repeats > 1 { format!(" (x{})", entry.repeats) } else { String::new() };
+        println!(
+            "{} {} {:>8} {}{}",
+            format!("{:>width$}", entry.number).yellow(),
+            entry.timestamp.format("%Y-%m-%d %H:%M").to_string().dimmed(),
+            status,
+            entry.command,
+            repeats.dimmed()
+        );
+        println!("{}                          {}", " ".repeat(width + 1), entry.task.dimmed());
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Usage {
+    pub count: usize,
+    pub executed: usize,
+    pub succeeded: usize,
+}
+
+impl Usage {
+    fn add(&mut self, entry: &HistoryEntry) {
+        self.count += 1;
+        if entry.executed {
+            self.executed += 1;
+        }
+        if entry.succeeded == Some(true) {
+            self.succeeded += 1;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub total: Usage,
+    pub tasks: HashMap<String, Usage>,
+    pub commands: HashMap<String, Usage>,
+}
+
+/// Aggregate usage counts in a single pass over the log.
+pub fn stats() -> Result<Stats, Error> {
+    let mut stats = Stats::default();
+    for_each_entry(|entry| {
+        stats.total.add(&entry);
+        stats.tasks.entry(entry.task.clone()).or_default().add(&entry);
+        stats.commands.entry(entry.command.clone()).or_default().add(&entry);
+    })?;
+    Ok(stats)
+}
+
+pub fn print_stats(stats: &Stats, limit: usize) {
+    if stats.total.count == 0 {
+        println!("No history yet.");
+        return;
+    }
+    println!(
+        "{} proposals, {} executed, {} succeeded",
+        stats.total.count, stats.total.executed, stats.total.succeeded
+    );
+    print_top("Most frequent commands:", &stats.commands, limit);
+    print_top("Most frequent tasks:", &stats.tasks, limit);
+}
+
+fn print_top(title: &str, usage: &HashMap<String, Usage>, limit: usize) {
+    let mut ranked: Vec<_> = usage.iter().collect();
+    ranked.sort_by(|a, b| b.1.count.cmp(&a.1.count).then_with(|| a.0.cmp(b.0)));
+    println!();
+    println!("{}", title.blue());
+    for (text, usage) in ranked.into_iter().take(limit) {
+        println!(
+            "{:>5}  {}  {}",
+            usage.count,
+            text,
+            format!("(ran {}/{}, succeeded {}/{})", usage.executed, usage.count, usage.succeeded, usage.executed).dimmed()
+        );
+    }
+}
@@ -1,317 +1,3069 @@
 mod auth;
+mod budget;
+mod config;
+mod context;
+mod error;
+mod explain;
+mod expand;
+mod highlight;
+mod history;
+mod net;
+mod provider;
+mod risk;
+mod shell_init;
+mod sse;
 
-use clap::Parser;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use std::env;
 use serde::{Deserialize, Serialize};
 use reqwest::Client;
-use std::io::{self, Write};
-use std::process::Command;
-use std::sync::{Arc, Mutex};
+use std::io::{self, IsTerminal, Write};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use colored::*;
 use tokio::time::timeout;
 use futures_util::StreamExt;
 
+use error::Error;
+use net::RequestExt;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct Message {
     role: String,
     content: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct CommandProposal {
     command: String,
     explanation: Option<String>,
     summary: Option<String>,
+    /// The command only makes sense in the user's own shell (cd, export, ...).
+    #[serde(default)]
+    run_in_shell: bool,
+    /// The stream was cut off, so the command may be truncated.
+    #[serde(skip)]
+    incomplete: bool,
+}
+
+impl Args {
+    /// Whether to print just the command instead of reviewing it.
+    fn command_only(&self) -> bool {
+        self.command_only_stdout || self.quiet
+    }
+
+    fn summary_mode(&self) -> SummaryMode {
+        if self.no_summary {
+            SummaryMode::Never
+        } else if self.force_summary {
+            SummaryMode::Always
+        } else {
+            SummaryMode::Auto
+        }
+    }
+}
+
+impl CommandProposal {
+    /// A proposal for a command the user typed themselves.
+    fn literal(command: &str) -> Self {
+        Self {
+            command: command.to_string(),
+            explanation: None,
+            summary: None,
+            run_in_shell: false,
+            incomplete: false,
+        }
+    }
 }
 
 #[derive(Parser)]
 #[command(name = "th")]
 #[command(about = "A command assistant tool")]
+#[command(args_conflicts_with_subcommands = true, disable_help_subcommand = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// Task description
     task: Vec<String>,
-}
 
+    /// Send the home directory and username to the API unredacted
+    #[arg(long)]
+    no_redact_paths: bool,
 
+    /// Extra JSON object merged into the chat completions payload
+    #[arg(long, value_name = "JSON")]
+    model_params: Option<String>,
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
-    let raw_query = args.task.join(" ").trim().to_string();
+    /// Print only the command on stdout; explanation and warnings go to stderr
+    /// and nothing is executed, e.g. `cmd=$(th --command-only-stdout "task")`
+    #[arg(long)]
+    command_only_stdout: bool,
 
-    if raw_query.is_empty() {
-        eprintln!("Usage: {} <task description>", env::args().next().unwrap_or("th".to_string()));
-        std::process::exit(1);
-    }
+    /// Print only the command on stdout, with no spinner, explanation, colour
+    /// or prompt, and run nothing, e.g. `CMD=$(th --quiet "task")`
+    #[arg(short, long, conflicts_with_all = ["literal", "interactive", "iterate", "json", "command_only_stdout"])]
+    quiet: bool,
 
-    // Check if we have a valid token, if not, login
-    if auth::access().await?.is_none() {
-        println!("No valid Copilot token found. Initiating login...");
-        let device_auth = auth::authorize().await?;
-        println!("Please visit {} and enter code: {}", device_auth.verification_uri, device_auth.user_code);
+    /// How to display proposals
+    #[arg(long, value_enum, default_value_t = OutputFormat::Plain)]
+    format: OutputFormat,
 
-        let mut poll_interval = (device_auth.interval as u64).max(1);
-        loop {
-            match auth::poll(&device_auth.device_code).await? {
-                auth::PollResult::Complete => {
-                    println!("Login successful!");
-                    break;
-                }
-                auth::PollResult::Pending => {}
-                auth::PollResult::SlowDown => {
-                    poll_interval = (poll_interval * 2).min(60);
-                }
-                auth::PollResult::Failed(err) => {
-                    eprintln!("Login failed: {}", err);
-                    std::process::exit(1);
-                }
-            }
-            tokio::time::sleep(Duration::from_secs(poll_interval)).await;
-        }
-    }
+    /// Print the effective configuration and where each value comes from
+    #[arg(long)]
+    print_config: bool,
 
-    let mut spinner = Spinner::new("Planning command…".to_string());
+    /// Text shown next to the progress spinner
+    #[arg(long, global = true)]
+    label: Option<String>,
 
-    let context = gather_context();
-    let messages = build_prompt(&raw_query, &context);
+    /// Review and run this exact command without asking the model
+    #[arg(long, value_name = "COMMAND", conflicts_with = "task")]
+    literal: Option<String>,
 
-    let proposal = timeout(Duration::from_secs(30), request_command(&messages)).await;
+    /// Run the proposed command without asking for approval
+    #[arg(short, long, conflicts_with = "step_confirm")]
+    yes: bool,
 
-    match proposal {
-        Ok(Ok(Some(proposal))) => {
-            spinner.stop();
-            render_proposal(&proposal);
+    /// When the approved command fails, send its output back and offer a
+    /// corrected command, up to three times. Output is captured rather than
+    /// streamed to the terminal while the command runs
+    #[arg(long, conflicts_with_all = ["interactive", "command_only_stdout"])]
+    iterate: bool,
 
-            if request_approval().await {
-                if let Err(e) = execute_command(&proposal.command).await {
-                    eprintln!("Command execution failed: {}", e);
-                }
-            } else {
-                println!("{}", "Command execution cancelled.".yellow());
-            }
+    /// Ask for up to N different commands and pick one by number
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=9))]
+    alternatives: u8,
+
+    /// Print the proposal as JSON and exit without prompting or running it
+    #[arg(long, conflicts_with_all = ["literal", "interactive", "iterate", "command_only_stdout"])]
+    json: bool,
+
+    /// Show the proposal without asking for approval or running it
+    #[arg(long, conflicts_with = "yes")]
+    dry_run: bool,
+
+    /// Before approval, list what the command's variables and globs expand
+    /// to here, without running anything
+    #[arg(long)]
+    preview_expand: bool,
+
+    /// Ask before running even commands matched by the `trusted_commands`
+    /// config key
+    #[arg(long)]
+    no_allowlist: bool,
+
+    /// Copy the proposed command to the clipboard instead of running it
+    #[arg(long, conflicts_with_all = ["yes", "dry_run", "json", "command_only_stdout"])]
+    copy: bool,
+
+    /// Confirm each statement of a multi-statement command separately; the
+    /// approved statements then run together in one shell
+    #[arg(long)]
+    step_confirm: bool,
+
+    /// Reject proposals that chain several statements with newlines, `;`,
+    /// `&&` or `||`; a single pipeline is still allowed
+    #[arg(long)]
+    single: bool,
+
+    /// Never show a summary, even if the model returns one
+    #[arg(long, conflicts_with = "force_summary")]
+    no_summary: bool,
+
+    /// Always ask the model for a summary of the command
+    #[arg(long)]
+    force_summary: bool,
+
+    /// Don't gather any context about the environment; faster and more
+    /// private, but suggestions may be less accurate
+    #[arg(long, conflicts_with = "assume_context")]
+    no_context: bool,
+
+    /// Send exactly this text as the context instead of gathering it
+    #[arg(long, value_name = "TEXT")]
+    assume_context: Option<String>,
+
+    /// Include the names in the current directory (up to 50, no dotfiles) in
+    /// the context, those sharing words with the task first
+    #[arg(long)]
+    list_files: bool,
+
+    /// List at most this many of the most relevant names; implies --list-files
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_context_files: Option<u64>,
+
+    /// Include the git diff in the context: staged, unstaged or all (default)
+    #[arg(long, value_enum, value_name = "SCOPE", num_args = 0..=1, require_equals = true, default_missing_value = "all")]
+    diff_context: Option<context::DiffScope>,
+
+    /// Run this command and include its output in the context; repeatable,
+    /// e.g. `--context-command "docker ps"`. Slow commands are killed after
+    /// 10 seconds and failures only produce a warning
+    #[arg(long, value_name = "COMMAND", conflicts_with = "assume_context")]
+    context_command: Vec<String>,
+
+    /// Run the approved command inside a throwaway docker/podman container of
+    /// this image, with the current directory mounted at /work
+    #[arg(long, value_name = "IMAGE")]
+    in_container: Option<String>,
+
+    /// Shell to run the approved command with; defaults to the `shell`
+    /// config key, then $SHELL, then bash
+    #[arg(long)]
+    shell: Option<String>,
+
+    /// Kill the approved command, along with everything it started, if it is
+    /// still running after this many seconds. It then gets no terminal input
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    exec_timeout: Option<u64>,
+
+    /// Also write the proposal to this path as an executable script
+    #[arg(long, value_name = "PATH")]
+    save_proposal: Option<std::path::PathBuf>,
+
+    /// Sampling seed for reproducible proposals; determinism depends on the
+    /// backend honoring it, so pair with a temperature of 0 where possible
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Start a session that plans, reviews and runs one task per line until
+    /// an empty line, `exit` or end of input; earlier tasks are remembered as
+    /// context. Running `th` with no task on a terminal does the same
+    #[arg(short, long, conflicts_with_all = ["task", "literal", "command_only_stdout"])]
+    interactive: bool,
+
+    /// Model to plan with, overriding the `model` config key
+    #[arg(long, global = true)]
+    model: Option<String>,
+
+    /// Sampling temperature from 0.0 to 2.0, overriding the `temperature`
+    /// config key
+    #[arg(long, global = true)]
+    temperature: Option<f64>,
+
+    /// Token cap for the model's response, overriding the `max_tokens` config
+    /// key; raise it if long commands come back cut off
+    #[arg(long, global = true)]
+    max_tokens: Option<u32>,
+
+    /// Seconds to wait for a model response, overriding the `timeout` config
+    /// key; 0 waits indefinitely
+    #[arg(long, global = true, value_name = "SECS")]
+    timeout: Option<u64>,
+
+    /// Chat API to plan with, overriding the `provider` config key; OpenAI
+    /// and Anthropic need an API key stored with `th login --provider`
+    #[arg(long, global = true, value_enum)]
+    provider: Option<provider::Provider>,
+
+    /// Chat API base URL, such as a proxy, overriding the `endpoints.api`
+    /// config key; `/chat/completions` is appended
+    #[arg(long, global = true, value_name = "URL")]
+    api_base: Option<String>,
+
+    /// Log requests (with the token redacted), response statuses, raw model
+    /// output and auth decisions to stderr
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Print how many prompt and completion tokens the request used (also
+    /// shown with --verbose)
+    #[arg(long)]
+    usage: bool,
+
+    /// Syntax-highlight the proposed command (also enabled by the `highlight`
+    /// config key)
+    #[arg(long, global = true)]
+    highlight: bool,
+
+    /// Print long reasons and summaries inline instead of paging them
+    /// through `$PAGER`
+    #[arg(long)]
+    no_pager: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Labelled command, reason and summary lines
+    Plain,
+    /// Aligned table with one row per command
+    Table,
+}
+
+/// Knobs that change the system prompt.
+#[derive(Debug, Default)]
+struct PromptOptions {
+    summary: SummaryMode,
+    /// Trash tool to recommend instead of `rm`, when `prefer_trash` is set.
+    trash_command: Option<String>,
+    /// Shell the command will run in, so its syntax can be matched.
+    shell: String,
+    /// Replacement system prompt read from `prompt.txt`.
+    template: Option<String>,
+    /// Detected operating system, unless context gathering is turned off.
+    platform: Option<String>,
+    /// How many alternative commands to ask for.
+    alternatives: u8,
+    /// Content piped to th on stdin, e.g. a log to ask about.
+    input: Option<String>,
+}
+
+impl PromptOptions {
+    fn trash_instruction(&self) -> String {
+        match &self.trash_command {
+            Some(trash) => format!(" Never delete files with rm; move them to the trash with `{}` instead.", trash),
+            None => String::new(),
         }
-        Ok(Ok(None)) => {
-            spinner.stop();
-            eprintln!("{}", "No command proposal returned. Please try rephrasing the request.".red());
-            std::process::exit(1);
+    }
+
+    fn shell_instruction(&self) -> String {
+        if self.shell.is_empty() {
+            return String::new();
         }
-        Ok(Err(e)) => {
-            spinner.stop();
-            eprintln!("Failed to query API: {}", e);
-            std::process::exit(1);
+        format!(" The command will be run by {}, so use its syntax.", self.shell)
+    }
+
+    fn alternatives_instruction(&self) -> String {
+        if self.alternatives <= 1 {
+            return String::new();
         }
-        Err(_) => {
-            spinner.stop();
-            eprintln!("{}", "API request timed out.".red());
-            std::process::exit(1);
+        format!(
+            " Return up to {} different ways to do the task, best first, as {{\"alternatives\": [...]}} where each element is an object with the fields above.",
+            self.alternatives
+        )
+    }
+
+    fn platform_instruction(&self) -> String {
+        match &self.platform {
+            Some(platform) => format!(" The user is on {}; use the package manager and tools that come with it.", platform),
+            None => String::new(),
         }
     }
+}
 
-    Ok(())
+/// Whether the model should include the optional `summary` field.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum SummaryMode {
+    /// Let the model decide
+    #[default]
+    Auto,
+    Always,
+    Never,
 }
 
-fn gather_context() -> String {
-    format!("current working directory: {}", env::current_dir().unwrap_or_default().display())
+impl SummaryMode {
+    fn fields(self) -> &'static str {
+        match self {
+            SummaryMode::Auto => ", and optionally \"summary\"",
+            SummaryMode::Always => ", and \"summary\"",
+            SummaryMode::Never => "",
+        }
+    }
+
+    fn instruction(self) -> &'static str {
+        match self {
+            SummaryMode::Auto => "Return \"summary\" only when the command involves multiple steps, non-trivial options, or could surprise the user; otherwise omit it.",
+            SummaryMode::Always => "Always return \"summary\": a short overview of what the command does and what it changes.",
+            SummaryMode::Never => "Do not return a \"summary\" field.",
+        }
+    }
 }
 
-fn build_prompt(task: &str, context: &str) -> Vec<Message> {
-    let system_message = Message {
-        role: "system".to_string(),
-        content: "You are a terminal command planner. Given a user request and project context, respond with ONLY a JSON object containing fields: \"command\", \"explanation\", and optionally \"summary\". Do not include any other text, explanations, or formatting. The \"command\" must be a single shell command. Example: {\"command\": \"ls\", \"explanation\": \"Lists files in the current directory\"}. Return \"summary\" only when the command involves multiple steps, non-trivial options, or could surprise the user; otherwise omit it. You must always propose a best-effort command even if information is missing—do not ask follow-up questions. If critical context is unavailable, make a reasonable assumption and mention it in \"explanation\". You cannot execute additional tools yourself; suggest only the command a user should run. If a safe command truly cannot be produced, return JSON with an empty \"command\" and a short explanation.".to_string(),
-    };
+#[derive(Subcommand)]
+enum Commands {
+    /// Persist the default model used for planning
+    PinModel {
+        /// Model name, e.g. gpt-4o-mini
+        #[arg(required_unless_present = "clear")]
+        name: Option<String>,
 
-    let user_message = Message {
-        role: "user".to_string(),
-        content: format!("Task: {}\n\nContext:\n{}", task, context),
-    };
+        /// Revert to the built-in default model for the configured provider
+        #[arg(long, conflicts_with = "name")]
+        clear: bool,
+    },
+    /// Explain what an existing command does without running it
+    Explain {
+        /// Command to explain; flags for th go before it, so
+        /// `th explain tar -xzf a.tgz` explains the whole tar invocation
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
 
-    vec![system_message, user_message]
+        /// Return structured fields (summary, flags, risks) rendered as a table
+        #[arg(long)]
+        explain_json: bool,
+
+        /// Print the structured explanation as raw JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show previously proposed commands
+    History {
+        /// Number of recent entries to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+
+        /// Collapse repeated identical task/command pairs into one entry
+        #[arg(long)]
+        dedupe: bool,
+
+        /// Show the most frequent tasks and commands with success rates
+        #[arg(long, conflicts_with = "dedupe")]
+        stats: bool,
+
+        /// Delete the history log
+        #[arg(long, conflicts_with_all = ["dedupe", "stats"])]
+        clear: bool,
+    },
+    /// Review and re-run the command from a `th history` entry without asking
+    /// the model again
+    Run {
+        /// Entry number as shown by `th history`
+        number: usize,
+    },
+    /// Review and run the last proposal again, with its explanation, without
+    /// asking the model again
+    #[command(alias = "!!")]
+    Retry,
+    /// Check the full API round-trip: token refresh plus a trivial planning request
+    #[command(alias = "ping")]
+    TestAuth,
+    /// Print a shell function that lets th change the calling shell's state,
+    /// e.g. `eval "$(th shell-init bash)"` or `th shell-init fish | source`
+    ShellInit {
+        #[arg(value_enum)]
+        shell: shell_init::Shell,
+    },
+    /// Print a completion script, e.g. `th completions zsh > ~/.zfunc/_th`
+    #[command(hide = true)]
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// View or change the defaults stored in config.toml
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Sign in to GitHub Copilot with the device-code flow, or store an API
+    /// key when used with `--provider openai` or `--provider anthropic`
+    Login {
+        /// Sign in again even if a valid token is already stored
+        #[arg(long)]
+        force: bool,
+    },
+    /// Remove the stored credentials for the selected provider
+    Logout,
+    /// Render a command the way proposals are shown, with syntax highlighting,
+    /// without running it
+    Preview {
+        /// Command to render
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
 }
 
-async fn request_command(messages: &[Message]) -> Result<Option<CommandProposal>, Box<dyn std::error::Error>> {
-    let client = Client::new();
-    let token = auth::access().await?.ok_or("No valid Copilot token. Please run 'th login' first.")?;
-    let url = "https://api.githubcopilot.com/chat/completions";
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Print the effective value of a key
+    Get {
+        #[arg(value_parser = clap::builder::PossibleValuesParser::new(config::KEYS))]
+        key: String,
+    },
+    /// Persist a value for a key
+    Set {
+        #[arg(value_parser = clap::builder::PossibleValuesParser::new(config::KEYS))]
+        key: String,
+        value: String,
+    },
+    /// Remove a key so its default applies again
+    Unset {
+        #[arg(value_parser = clap::builder::PossibleValuesParser::new(config::KEYS))]
+        key: String,
+    },
+    /// Print every key with its value and where it comes from
+    List,
+}
 
-    let payload = serde_json::json!({
-        "model": "gpt-4o",
-        "messages": messages,
-        "temperature": 0.2,
-        "max_tokens": 180,
-        "stream": true
-    });
+/// Most corrected commands `--iterate` asks for after a failure.
+const MAX_REPAIR_ATTEMPTS: usize = 3;
 
-    let response = client
-        .post(url)
-        .header("Authorization", format!("Bearer {}", token))
-        .header("Content-Type", "application/json")
-        .header("Editor-Version", "vscode/1.99.3")
-        .header("Editor-Plugin-Version", "copilot-chat/0.26.7")
-        .json(&payload)
-        .send()
-        .await?;
+/// Most bytes of piped stdin sent along with the task.
+const MAX_PIPED_INPUT_BYTES: usize = 16000;
 
-    if response.status().is_success() {
-        let mut buffer = String::new();
-        let mut stream = response.bytes_stream();
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk?;
-            let text = std::str::from_utf8(&chunk).unwrap_or("");
-            buffer.push_str(text);
-        }
-        if !buffer.is_empty() {
-            if let Some(proposal) = parse_streaming_proposal(&buffer) {
-                return Ok(Some(proposal));
-            }
-        }
-        Ok(None)
-    } else {
-        let status = response.status();
-        let error_text = response.text().await.unwrap_or_default();
-        eprintln!("API request failed: {} {}", status, error_text);
-        Ok(None)
+/// Most bytes of a failed command's output sent back under `--iterate`.
+const MAX_REPAIR_OUTPUT_BYTES: usize = 4000;
+
+/// Payload keys that th sets itself and that `--model-params` may not override.
+const MANAGED_PAYLOAD_KEYS: [&str; 3] = ["messages", "model", "stream"];
+
+#[derive(Debug, Default)]
+struct RequestOptions {
+    provider: provider::Provider,
+    model: String,
+    temperature: f64,
+    /// How long to wait for the whole response; `None` waits indefinitely.
+    timeout: Option<Duration>,
+    max_tokens: u32,
+    model_params: serde_json::Map<String, serde_json::Value>,
+    seed: Option<u64>,
+    /// Chat API base from the `endpoints.api` config key or `--api-base`.
+    api_base: Option<String>,
+}
+
+
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("{}", e.to_string().red());
+        std::process::exit(e.exit_code());
     }
 }
 
-fn parse_streaming_proposal(content: &str) -> Option<CommandProposal> {
-    let mut accumulated_content = String::new();
-    // Handle SSE format: split by "data: " and parse each JSON
-    for line in content.lines() {
-        if line.starts_with("data: ") {
-            let data = &line[6..]; // Remove "data: "
-            if data == "[DONE]" {
-                continue;
-            }
-            if let Ok(json_value) = serde_json::from_str::<serde_json::Value>(data) {
-                if let Some(delta) = json_value["choices"][0]["delta"].as_object() {
-                    if let Some(content_str) = delta.get("content").and_then(|c| c.as_str()) {
-                        accumulated_content.push_str(content_str);
-                    }
-                }
-            }
+async fn run() -> Result<(), Error> {
+    let args = Args::parse();
+    configure_colors(&args);
+    if args.quiet {
+        colored::control::set_override(false);
+    }
+    net::set_verbose(args.verbose);
+
+    if let Some(command) = &args.command {
+        return run_subcommand(command, &args).await;
+    }
+
+    let resolved = load_config(&args)?;
+
+    if args.print_config {
+        print_config(&resolved);
+        return Ok(());
+    }
+    let config = resolved.config;
+    configure_budget(&config);
+    configure_spinner(&config);
+
+    if let Some(command) = &args.literal {
+        let proposal = CommandProposal::literal(command.trim());
+        if let Review::Done(Some(entry)) = review_and_run(&proposal, command.trim(), &args, &config, false).await? {
+            exit_with_outcome(&entry);
         }
+        return Ok(());
     }
-    // After accumulating, use extract_json to find the JSON in the content
-    if !accumulated_content.is_empty() {
-        if let Some(json) = extract_json(&accumulated_content) {
-            let command = json.get("command")?.as_str()?.trim().to_string();
-            let explanation = json.get("explanation").and_then(|v| v.as_str()).map(|s| s.trim().to_string());
-            let summary = json.get("summary").and_then(|v| v.as_str()).map(|s| s.trim().to_string());
-            if !command.is_empty() {
-                Some(CommandProposal { command, explanation, summary })
+
+    let raw_query = args.task.join(" ").trim().to_string();
+
+    let interactive = args.interactive || (raw_query.is_empty() && io::stdin().is_terminal());
+    if raw_query.is_empty() && !interactive {
+        eprintln!("Usage: {} <task description>", env::args().next().unwrap_or("th".to_string()));
+        std::process::exit(1);
+    }
+
+    let mut options = RequestOptions { seed: args.seed, ..RequestOptions::new(&config) };
+    options.max_tokens = options.max_tokens.saturating_mul(u32::from(args.alternatives));
+    if let Some(raw) = &args.model_params {
+        options.model_params = parse_model_params(raw).map_err(|e| Error::Parse(format!("Invalid --model-params: {}", e)))?;
+    }
+
+    ensure_logged_in(&config).await?;
+
+    let context = build_context(&raw_query, &args, &config);
+
+    if interactive {
+        return run_interactive(&context, &args, &config, &options).await;
+    }
+
+    // Piped stdin, as in `cat error.log | th "why did this fail"`, is sent
+    // along with the task; it also means the approval prompt can't be answered
+    let input = context::piped_input(MAX_PIPED_INPUT_BYTES);
+    if input.is_some() && !args.yes && !config.auto_approve() && !args.dry_run && !args.json && !args.command_only() {
+        eprintln!(
+            "{} stdin was read as input, so the command can't be confirmed and won't run; pass --yes to run it",
+            "Note:".yellow()
+        );
+    }
+    let mut messages = prompt_messages(&raw_query, &context, input.as_deref(), &args, &config);
+    let mut repairs = 0;
+    loop {
+        let proposals = plan_command(&messages, &args, &options).await?;
+        save_last_proposal(&raw_query, &proposals[0]);
+        if args.json {
+            let json = if args.alternatives > 1 {
+                serde_json::to_string_pretty(&proposals)?
             } else {
-                None
+                serde_json::to_string_pretty(&proposals[0])?
+            };
+            println!("{}", json);
+            return Ok(());
+        }
+        if args.command_only() {
+            if !args.quiet {
+                render_proposal_notes(&proposals[0], &proposal_warnings(&proposals[0], &config));
             }
-        } else {
-            None
+            println!("{}", proposals[0].command);
+            return Ok(());
         }
-    } else {
-        None
+        let mut entry = match review(&proposals, &raw_query, &args, &config).await? {
+            Review::Done(Some(entry)) => entry,
+            Review::Done(None) => break,
+            Review::Regenerate => {
+                push_rejection(&mut messages, &proposals[0].command);
+                continue;
+            }
+        };
+        let Some(output) = entry.output.take().filter(|_| args.iterate && repairs < MAX_REPAIR_ATTEMPTS) else {
+            exit_with_outcome(&entry);
+            break;
+        };
+        repairs += 1;
+        println!("{}", format!("Asking for a fix (attempt {}/{})…", repairs, MAX_REPAIR_ATTEMPTS).yellow());
+        messages.push(Message {
+            role: "assistant".to_string(),
+            content: serde_json::json!({ "command": entry.command }).to_string(),
+        });
+        messages.push(Message {
+            role: "user".to_string(),
+            content: format!(
+                "That command failed. Its output was:\n{}\n\nPropose a corrected command for the original task.",
+                output
+            ),
+        });
     }
+
+    Ok(())
 }
 
-fn extract_json(content: &str) -> Option<serde_json::Value> {
-    let trimmed = content.trim();
-    // Try direct parsing first
-    if let Ok(json) = serde_json::from_str(trimmed) {
-        return Some(json);
+/// Exit with the failed command's own exit code, or 130 if the user
+/// cancelled it, so th behaves in `&&` chains and Makefiles. Returns when the
+/// command succeeded or was handed to the calling shell.
+fn exit_with_outcome(entry: &history::HistoryEntry) {
+    if !entry.executed {
+        std::process::exit(130);
     }
-    // Try adding } at the end, in case the JSON is truncated
-    let with_brace = trimmed.to_string() + "}";
-    if let Ok(json) = serde_json::from_str(&with_brace) {
-        return Some(json);
+    if entry.succeeded == Some(false) {
+        std::process::exit(entry.exit_code.unwrap_or(1));
     }
-    // Fallback to substring extraction
-    if let Some(start) = trimmed.find('{') {
-        if let Some(end) = trimmed.rfind('}') {
-            if end > start {
-                let json_str = &trimmed[start..=end];
-                return serde_json::from_str(json_str).ok();
-            }
+}
+
+/// Await `future`, giving up after `limit` if one is set.
+async fn within<F: std::future::Future>(
+    limit: Option<Duration>,
+    future: F,
+) -> Result<F::Output, tokio::time::error::Elapsed> {
+    match limit {
+        Some(limit) => timeout(limit, future).await,
+        None => Ok(future.await),
+    }
+}
+
+fn timed_out_message(options: &RequestOptions) -> String {
+    let secs = options.timeout.map(|t| t.as_secs()).unwrap_or_default();
+    format!("API request timed out after {}s. Use --timeout to wait longer (0 for no limit).", secs)
+}
+
+/// Gather the context sent along with each task, honouring the context flags.
+/// `task` decides which directory entries are listed first; it is empty for
+/// interactive sessions, which list them alphabetically.
+fn build_context(task: &str, args: &Args, config: &config::Config) -> String {
+    if let Some(assumed) = &args.assume_context {
+        return assumed.clone();
+    }
+    let mut context = String::new();
+    if !args.no_context {
+        context = gather_context(&target_shell(args, config));
+        let max_files = args.max_context_files.map_or(context::MAX_LISTED_FILES, |n| n as usize);
+        if (args.list_files || args.max_context_files.is_some())
+            && let Some(files) = context::list_files(task, max_files)
+        {
+            context.push_str(&format!("\nfiles in the current directory:\n{}", files));
+        }
+        if let Some(scope) = args.diff_context
+            && let Some(diff) = context::git_diff(scope)
+        {
+            context.push_str(&format!("\ngit diff:\n{}", diff));
+        }
+        if let Some((path, notes)) = context::project_file() {
+            context.push_str(&format!("\nproject notes from {}:\n{}", path.display(), notes));
         }
     }
-    None
+    // Asked for explicitly, so these run even with --no-context
+    for command in &args.context_command {
+        match context::command_output(command) {
+            Ok(output) => context.push_str(&format!("\noutput of `{}`:\n{}", command, output)),
+            Err(e) => eprintln!("{} --context-command `{}` {}; leaving it out", "Warning:".yellow(), command, e),
+        }
+    }
+    let mut context = context.trim_start().to_string();
+    if !args.no_redact_paths {
+        context = redact_paths(&context);
+    }
+    context
 }
 
-struct Spinner {
-    running: Arc<Mutex<bool>>,
-    handle: Option<thread::JoinHandle<()>>,
+/// The planning conversation for `task`, with the prompt options taken from
+/// the flags and config.
+fn prompt_messages(task: &str, context: &str, input: Option<&str>, args: &Args, config: &config::Config) -> Vec<Message> {
+    let prompt = PromptOptions {
+        summary: args.summary_mode(),
+        trash_command: trash_command(config),
+        shell: shell_name(&target_shell(args, config)).to_string(),
+        template: get_prompt_template_path().ok().and_then(|path| std::fs::read_to_string(path).ok()),
+        platform: (!args.no_context && args.assume_context.is_none()).then(context::platform),
+        alternatives: args.alternatives,
+        input: input.map(str::to_string),
+    };
+    build_prompt(task, context, &prompt)
 }
 
-impl Spinner {
-    fn new(label: String) -> Self {
-        let running = Arc::new(Mutex::new(true));
-        let running_clone = running.clone();
-        let handle = thread::spawn(move || {
-            let frames = vec![':', '⁖', '⁘', '⁛', '⁙', '⁛', '⁘', '⁖'];
-            let mut index = 0;
-            while *running_clone.lock().unwrap() {
-                print!("\r{} {}", format!("{}", frames[index]).yellow(), label);
-                io::stdout().flush().unwrap();
-                thread::sleep(Duration::from_millis(140));
-                index = (index + 1) % frames.len();
+/// Ask the model for a command, or several with `--alternatives`, showing the
+/// spinner meanwhile. Errors display as user-facing messages.
+async fn plan_command(messages: &[Message], args: &Args, options: &RequestOptions) -> Result<Vec<CommandProposal>, Error> {
+    let label = args.label.clone().unwrap_or_else(|| "Planning command…".to_string());
+    let mut spinner = if args.json || args.quiet {
+        None
+    } else if args.command_only_stdout {
+        Some(Spinner::on_stderr(label.clone()))
+    } else {
+        Some(Spinner::new(label.clone()))
+    };
+
+    let planning = within(
+        options.timeout,
+        request_command(messages, options, |command| {
+            if let Some(spinner) = &spinner {
+                spinner.set_label(format!("{} {}", label, command));
             }
-        });
-        Self { running, handle: Some(handle) }
+        }),
+    );
+    let proposal = planning.await;
+    if let Some(spinner) = &mut spinner {
+        spinner.stop();
+    }
+    if args.verbose
+        && let Some(id) = net::sent_request_id()
+    {
+        eprintln!("{} {}", "request id:".dimmed(), id);
+    }
+    if (args.verbose || args.usage)
+        && let Some(usage) = net::last_usage()
+    {
+        eprintln!("{} {} prompt + {} completion", "tokens:".dimmed(), usage.prompt, usage.completion);
     }
 
-    fn stop(&mut self) {
-        *self.running.lock().unwrap() = false;
-        if let Some(handle) = self.handle.take() {
-            handle.join().unwrap();
+    match proposal {
+        Ok(Ok(mut proposals)) if !proposals.is_empty() => {
+            if args.no_summary {
+                for proposal in &mut proposals {
+                    proposal.summary = None;
+                }
+            }
+            if args.single {
+                let first = proposals[0].command.clone();
+                proposals.retain(|p| is_single_statement(&p.command));
+                if proposals.is_empty() {
+                    return Err(Error::Other(format!(
+                        "The model proposed several statements, which --single rejects:\n{}",
+                        first
+                    )));
+                }
+            }
+            Ok(proposals)
         }
-        print!("\r\x1b[K");
-        io::stdout().flush().unwrap();
+        Ok(Ok(_)) if let Ok(path) = get_prompt_template_path()
+            && path.exists() =>
+        {
+            Err(Error::Parse(format!(
+                "No command proposal returned. The custom prompt in {} must still ask for the JSON reply th expects.",
+                path.display()
+            )))
+        }
+        Ok(Ok(_)) => Err(Error::Parse("No command proposal returned. Please try rephrasing the request.".to_string())),
+        Ok(Err(e @ (Error::Unreachable(_) | Error::Api { .. } | Error::NotAuthenticated(_) | Error::NoSafeCommand(_)))) => Err(e),
+        Ok(Err(e)) => Err(Error::Other(format!("Failed to query API: {}", e))),
+        Err(_) => Err(Error::Other(timed_out_message(options))),
     }
 }
 
-impl Drop for Spinner {
-    fn drop(&mut self) {
-        if *self.running.lock().unwrap() {
-            *self.running.lock().unwrap() = false;
-            if let Some(handle) = self.handle.take() {
-                handle.join().unwrap();
+/// Read tasks from stdin until an empty line, `exit`, `quit` or end of input,
+/// planning and reviewing each one. The conversation grows with every task,
+/// its command and how it went, so follow-ups like "now do the same but
+/// recursively" can refer back to them.
+async fn run_interactive(
+    context: &str,
+    args: &Args,
+    config: &config::Config,
+    options: &RequestOptions,
+) -> Result<(), Error> {
+    println!("{}", "Type a task, or an empty line to leave.".dimmed());
+    let mut messages: Vec<Message> = Vec::new();
+    let mut outcome = String::new();
+    'tasks: loop {
+        print!("{} ", "th>".blue());
+        io::stdout().flush()?;
+        let input = read_line()?;
+        if input.is_empty() {
+            println!();
+            return Ok(());
+        }
+        let task = input.trim();
+        if matches!(task, "" | "exit" | "quit") {
+            return Ok(());
+        }
+
+        let turn = messages.len();
+        if messages.is_empty() {
+            messages = prompt_messages(task, context, None, args, config);
+        } else {
+            messages.push(Message {
+                role: "user".to_string(),
+                content: format!("{}\n\nNext task: {}", outcome, task),
+            });
+        }
+        let (proposals, entry) = loop {
+            let proposals = match plan_command(&messages, args, options).await {
+                Ok(proposals) => proposals,
+                Err(e) => {
+                    eprintln!("{}", e.to_string().red());
+                    // Forget the failed turn so the conversation stays well-formed
+                    messages.truncate(turn);
+                    continue 'tasks;
+                }
+            };
+            save_last_proposal(task, &proposals[0]);
+            match review(&proposals, task, args, config).await? {
+                Review::Done(entry) => break (proposals, entry),
+                Review::Regenerate => push_rejection(&mut messages, &proposals[0].command),
             }
-            print!("\r\x1b[K");
-            io::stdout().flush().unwrap();
+        };
+        let command = entry.as_ref().map_or(&proposals[0].command, |entry| &entry.command);
+        messages.push(Message {
+            role: "assistant".to_string(),
+            content: serde_json::json!({ "command": command }).to_string(),
+        });
+        outcome = match entry.map(|entry| (entry.executed, entry.succeeded)) {
+            None => "That command was not run (dry run).",
+            Some((false, _)) => "The user rejected that command.",
+            Some((true, Some(false))) => "That command ran and failed.",
+            Some((true, _)) => "That command ran successfully.",
         }
+        .to_string();
     }
 }
 
-async fn request_approval() -> bool {
-    print!("{} Execute this command? (y/N): ", "  ->".yellow());
-    io::stdout().flush().unwrap();
-    let mut input = String::new();
-    io::stdin().read_line(&mut input).unwrap();
-    input.trim().to_lowercase().starts_with('y')
+/// How reviewing a proposal ended.
+enum Review {
+    /// The command ran or was rejected, as recorded in the history entry;
+    /// `None` when nothing was recorded, as for a dry run.
+    Done(Option<history::HistoryEntry>),
+    /// The user asked for a different proposal instead.
+    Regenerate,
 }
 
-async fn execute_command(command: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let status = Command::new("bash")
-        .arg("-lc")
-        .arg(command)
-        .current_dir(env::current_dir()?)
-        .status()?;
+/// Ask for a different approach after the user turned down `command`.
+fn push_rejection(messages: &mut Vec<Message>, command: &str) {
+    println!("{}", "Asking for a different command…".yellow());
+    messages.push(Message {
+        role: "assistant".to_string(),
+        content: serde_json::json!({ "command": command }).to_string(),
+    });
+    messages.push(Message {
+        role: "user".to_string(),
+        content: "The user rejected that suggestion. Propose a different approach to the same task.".to_string(),
+    });
+}
 
-    if status.success() {
-        Ok(())
+/// Review a single proposal, or let the user pick among alternatives.
+async fn review(
+    proposals: &[CommandProposal],
+    task: &str,
+    args: &Args,
+    config: &config::Config,
+) -> Result<Review, Error> {
+    if proposals.len() > 1 {
+        review_alternatives(proposals, task, args, config).await
     } else {
-        Err(format!("Command exited with code {:?}", status.code()).into())
+        review_and_run(&proposals[0], task, args, config, true).await
     }
 }
 
-fn render_proposal(proposal: &CommandProposal) {
-    println!("  {} {}", "command:".blue(), proposal.command.green());
+/// Show a proposal, ask for approval and run it, recording the outcome in
+/// the history log. With `regenerate`, the user may also ask for a different
+/// proposal instead.
+async fn review_and_run(
+    proposal: &CommandProposal,
+    task: &str,
+    args: &Args,
+    config: &config::Config,
+    regenerate: bool,
+) -> Result<Review, Error> {
+    let auto_approve = args.yes || config.auto_approve();
+    let trashed;
+    let proposal = match trash_command(config).and_then(|trash| substitute_trash(&proposal.command, &trash)) {
+        Some(command) if auto_approve || args.dry_run => {
+            show_trash_substitution(&proposal.command, &command);
+            trashed = CommandProposal { command, ..proposal.clone() };
+            &trashed
+        }
+        Some(command) if offer_trash_substitution(&proposal.command, &command) => {
+            trashed = CommandProposal { command, ..proposal.clone() };
+            &trashed
+        }
+        _ => proposal,
+    };
+
+    let exec = exec_options(args, config)?;
 
-    if let Some(explanation) = &proposal.explanation {
-        println!("  {} {}", "reason:".blue(), explanation.dimmed());
+    let warnings = proposal_warnings(proposal, config);
+    match args.format {
+        OutputFormat::Plain => render_proposal(proposal, &warnings, args.highlight || config.highlight(), !args.no_pager),
+        OutputFormat::Table => {
+            render_proposal_table(std::slice::from_ref(proposal));
+            render_warnings(&warnings);
+        }
+    }
+    if let Some(invocation) = describe_invocation(&proposal.command, &exec) {
+        println!("  {} {}", "runs as:".blue(), invocation.dimmed());
+        println!();
+    }
+    if let Some(path) = &args.save_proposal {
+        match save_proposal(proposal, path) {
+            Ok(()) => println!("  {} {}\n", "saved:".blue(), path.display()),
+            Err(e) => eprintln!("{} could not save {}: {}", "Warning:".yellow(), path.display(), e),
+        }
     }
 
-    if let Some(summary) = &proposal.summary {
-        println!("  {} {}", "summary:".blue(), summary.dimmed());
+    if args.preview_expand {
+        render_expansions(&proposal.command);
     }
 
-    println!();
-}
\ No newline at end of file
+    if args.dry_run {
+        println!("{}", "(dry run — not executing)".dimmed());
+        return Ok(Review::Done(None));
+    }
+    if args.copy {
+        copy_proposal(proposal)?;
+        return Ok(Review::Done(None));
+    }
+
+    let statements = if args.step_confirm { split_statements(&proposal.command) } else { Vec::new() };
+    let trusted = !args.no_allowlist && warnings.is_empty() && risk::is_trusted(&proposal.command, config.trusted_commands());
+    let approved = if auto_approve {
+        confirm_auto_approval(&proposal.command, config).await.then(|| {
+            println!("{} {}", "  ->".yellow(), "Approved automatically".dimmed());
+            proposal.command.clone()
+        })
+    } else if trusted {
+        println!("{} {}", "  ->".yellow(), "Trusted command, approved automatically".dimmed());
+        Some(proposal.command.clone())
+    } else if statements.len() > 1 {
+        confirm_statements(&statements, config)
+    } else {
+        let mut command = proposal.command.clone();
+        let mut strict = !warnings.is_empty();
+        loop {
+            match request_approval(strict, config.approval_timeout(), regenerate).await {
+                Approval::Run => break Some(command),
+                Approval::Cancel => break None,
+                Approval::Regenerate => return Ok(Review::Regenerate),
+                Approval::Edit => {
+                    command = edit_command(&command)?;
+                    println!("  {} {}", "edited:".blue(), command.green());
+                    let warnings = command_warnings(&command, config);
+                    render_warnings(&warnings);
+                    strict = !warnings.is_empty();
+                }
+            }
+        }
+    };
+
+    Ok(Review::Done(finish_review(proposal, approved, task, &exec, config).await?))
+}
+
+/// Run the approved command, or record the rejection when `approved` is
+/// `None`, and return the history entry.
+async fn finish_review(
+    proposal: &CommandProposal,
+    approved: Option<String>,
+    task: &str,
+    exec: &ExecOptions,
+    config: &config::Config,
+) -> Result<Option<history::HistoryEntry>, Error> {
+    if let Some(command) = approved {
+        let proposal = &CommandProposal { command, ..proposal.clone() };
+        let mut entry = history::HistoryEntry::new(task, &proposal.command, true);
+        if runs_in_shell(proposal)
+            && exec.container.is_none()
+            && let Ok(eval_file) = env::var(shell_init::EVAL_FILE_ENV)
+        {
+            record_history(&entry);
+            std::fs::write(&eval_file, &proposal.command)?;
+            return Ok(Some(entry));
+        }
+        let result = execute_command(&proposal.command, exec).await;
+        entry.succeeded = Some(result.is_ok());
+        record_history(&entry);
+        if let Err(e) = result {
+            eprintln!("Command execution failed: {}", e.message);
+            entry.output = e.output;
+            entry.exit_code = e.code;
+        }
+        notify_completion(config);
+        Ok(Some(entry))
+    } else {
+        let mut entry = history::HistoryEntry::new(task, &proposal.command, false);
+        if config.prompt_rejection_reason() {
+            entry.rejection_reason = request_rejection_reason();
+        }
+        record_history(&entry);
+        println!("{}", "Command execution cancelled.".yellow());
+        Ok(Some(entry))
+    }
+}
+
+/// List the alternatives by number and review the one the user picks like a
+/// single proposal. Anything other than a listed number cancels.
+async fn review_alternatives(
+    proposals: &[CommandProposal],
+    task: &str,
+    args: &Args,
+    config: &config::Config,
+) -> Result<Review, Error> {
+    match args.format {
+        OutputFormat::Plain => {
+            for (i, proposal) in proposals.iter().enumerate() {
+                let command = if args.highlight || config.highlight() {
+                    highlight::highlight(&proposal.command)
+                } else {
+                    proposal.command.green().to_string()
+                };
+                println!("  {} {}", format!("[{}]", i + 1).blue(), command);
+                if let Some(explanation) = &proposal.explanation {
+                    println!("      {}", explanation.dimmed());
+                }
+                for warning in proposal_warnings(proposal, config) {
+                    println!("      {} {}", "warning:".red(), warning.red());
+                }
+            }
+        }
+        OutputFormat::Table => {
+            render_proposal_table(proposals);
+            for (i, proposal) in proposals.iter().enumerate() {
+                for warning in proposal_warnings(proposal, config) {
+                    println!("  {} {} {}", format!("[{}]", i + 1).blue(), "warning:".red(), warning.red());
+                }
+            }
+        }
+    }
+    println!();
+
+    if args.dry_run {
+        println!("{}", "(dry run — not executing)".dimmed());
+        return Ok(Review::Done(None));
+    }
+
+    let chosen = if args.yes || config.auto_approve() {
+        println!("{} {}", "  ->".yellow(), "Picking [1] automatically".dimmed());
+        Some(&proposals[0])
+    } else {
+        print!("{} Pick a command to review (1-{}, anything else cancels): ", "  ->".yellow(), proposals.len());
+        io::stdout().flush()?;
+        let input = read_line()?;
+        input.trim().parse::<usize>().ok().and_then(|n| n.checked_sub(1)).and_then(|i| proposals.get(i))
+    };
+    let Some(chosen) = chosen else {
+        let exec = exec_options(args, config)?;
+        return Ok(Review::Done(finish_review(&proposals[0], None, task, &exec, config).await?));
+    };
+    println!();
+    review_and_run(chosen, task, args, config, false).await
+}
+
+/// Whether an automatic approval from `--yes` or `auto_approve` stands for
+/// `command`. Destructive commands still need a typed "yes", and are refused
+/// when there is no terminal to type it on.
+async fn confirm_auto_approval(command: &str, config: &config::Config) -> bool {
+    let risk::Risk::Dangerous(reason) = risk::risk_level(command, config.dangerous_patterns()) else {
+        return true;
+    };
+    if !io::stdin().is_terminal() {
+        eprintln!(
+            "{} not running a potentially destructive command ({}) without a typed \"yes\"",
+            "Refusing:".red(),
+            reason
+        );
+        return false;
+    }
+    println!("{} {}", "  ->".red(), "Automatic approval doesn't cover destructive commands".dimmed());
+    request_approval(true, config.approval_timeout(), false).await == Approval::Run
+}
+
+/// Execution settings from the flags, or why they can't be satisfied.
+fn exec_options(args: &Args, config: &config::Config) -> Result<ExecOptions, Error> {
+    Ok(ExecOptions::from_args(args, config)?)
+}
+
+impl RequestOptions {
+    fn new(config: &config::Config) -> Self {
+        Self {
+            provider: config.provider(),
+            model: config.model().to_string(),
+            temperature: config.temperature(),
+            timeout: config.timeout(),
+            max_tokens: config.max_tokens(),
+            api_base: config.api_base().map(str::to_string),
+            ..Default::default()
+        }
+    }
+}
+
+/// Spinner frames and interval, set once from the config.
+static SPINNER_STYLE: std::sync::OnceLock<(Vec<char>, Duration)> = std::sync::OnceLock::new();
+
+/// Pick the spinner frames and interval from the config, falling back to
+/// ASCII frames under a non-UTF-8 locale or on the Linux console.
+fn configure_spinner(config: &config::Config) {
+    let spinner = config.spinner.clone().unwrap_or_default();
+    let non_utf8 = ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .find_map(|var| env::var(var).ok().filter(|v| !v.is_empty()))
+        .is_some_and(|locale| !locale.to_uppercase().replace('-', "").contains("UTF8"));
+    let frames = match spinner.frames.as_deref() {
+        Some("ascii") => ASCII_SPINNER_FRAMES.to_string(),
+        Some(frames) => frames.to_string(),
+        None if non_utf8 || env::var("TERM").is_ok_and(|t| t == "linux") => ASCII_SPINNER_FRAMES.to_string(),
+        None => DEFAULT_SPINNER_FRAMES.to_string(),
+    };
+    let interval = Duration::from_millis(spinner.interval_ms.unwrap_or(DEFAULT_SPINNER_INTERVAL_MS));
+    let _ = SPINNER_STYLE.set((frames.chars().collect(), interval));
+}
+
+fn configure_budget(config: &config::Config) {
+    budget::configure(config.retry_budget(), Duration::from_secs(config.retry_budget_secs()));
+}
+
+/// Check if we have a valid token, and if not, run the device-code login flow.
+async fn ensure_logged_in(config: &config::Config) -> Result<(), Error> {
+    let provider = config.provider();
+    if auth::token(provider).await?.is_none() {
+        if provider.uses_api_key() {
+            return Err(login_hint(provider));
+        }
+        eprintln!("No valid Copilot token found. Initiating login...");
+        login(config).await?;
+    }
+    Ok(())
+}
+
+fn login_hint(provider: provider::Provider) -> Error {
+    Error::NotAuthenticated(match provider {
+        provider::Provider::Copilot => "No valid Copilot token. Please run 'th login' first.".to_string(),
+        _ => format!("No {} API key stored. Please run 'th login --provider {}' first.", provider.name(), provider.name()),
+    })
+}
+
+/// Prompt for an API key without echoing it when stdin is a terminal.
+fn read_api_key(provider: provider::Provider) -> Result<String, Error> {
+    let prompt = format!("Enter your {} API key: ", provider.name());
+    let key = if io::stdin().is_terminal() {
+        rpassword::prompt_password(prompt)?
+    } else {
+        read_line()?
+    };
+    let key = key.trim().to_string();
+    if key.is_empty() {
+        return Err("No API key entered.".into());
+    }
+    Ok(key)
+}
+
+/// Run the device-code login flow. Fails if GitHub refuses, the code
+/// expires or the user presses Ctrl-C.
+async fn login(config: &config::Config) -> Result<(), Error> {
+    let device_auth = auth::authorize().await?;
+    eprintln!("Please visit {} and enter code: {}", device_auth.verification_uri, device_auth.user_code);
+
+    let limit = config.login_timeout.unwrap_or(device_auth.expires_in.max(1) as u64);
+    tokio::select! {
+        result = timeout(Duration::from_secs(limit), poll_login(&device_auth)) => {
+            result.map_err(|_| {
+                Error::NotAuthenticated(format!("Login timed out after {}s. Run th again to retry.", limit))
+            })?
+        }
+        _ = tokio::signal::ctrl_c() => Err(Error::Interrupted("Login cancelled.".to_string())),
+    }
+}
+
+async fn poll_login(device_auth: &auth::DeviceAuth) -> Result<(), Error> {
+    let mut poll_interval = (device_auth.interval as u64).max(1);
+    loop {
+        match auth::poll(&device_auth.device_code).await? {
+            auth::PollResult::Complete => {
+                eprintln!("Login successful!");
+                return Ok(());
+            }
+            auth::PollResult::Pending => {}
+            auth::PollResult::SlowDown => {
+                poll_interval = (poll_interval * 2).min(60);
+                budget::spend(Duration::from_secs(poll_interval))?;
+            }
+            auth::PollResult::Failed(err) => return Err(Error::NotAuthenticated(format!("Login failed: {}", err))),
+        }
+        tokio::time::sleep(Duration::from_secs(poll_interval)).await;
+    }
+}
+
+async fn run_subcommand(command: &Commands, args: &Args) -> Result<(), Error> {
+    match command {
+        Commands::PinModel { name, clear } => {
+            let mut config = config::load_writable()?;
+            let name = if *clear { None } else { name.as_deref() };
+            config.set("model", name)?;
+            config::save(&config)?;
+            match name {
+                Some(name) => println!("Default model set to {}", name.green()),
+                None => println!("Default model reset to {}", load_config(args)?.config.model().green()),
+            }
+        }
+        Commands::Explain { command, explain_json, json } => {
+            let config = load_config(args)?.config;
+            configure_budget(&config);
+            configure_spinner(&config);
+            ensure_logged_in(&config).await?;
+            explain::run(&command.join(" "), *explain_json, *json, args.label.as_deref(), &config).await?;
+        }
+        Commands::History { limit, dedupe, stats, clear } => {
+            if *clear {
+                if history::clear()? {
+                    println!("History cleared");
+                } else {
+                    println!("No history to clear");
+                }
+            } else if *stats {
+                history::print_stats(&history::stats()?, *limit);
+            } else {
+                let mut entries = history::load()?;
+                if *dedupe {
+                    entries = history::dedupe(entries);
+                }
+                history::print_entries(&entries, *limit);
+            }
+        }
+        Commands::TestAuth => {
+            let config = load_config(args)?.config;
+            configure_budget(&config);
+            if !test_auth(&config).await {
+                std::process::exit(1);
+            }
+        }
+        Commands::ShellInit { shell } => {
+            print!("{}", shell_init::script(*shell));
+        }
+        Commands::Completions { shell } => {
+            clap_complete::generate(*shell, &mut Args::command(), "th", &mut io::stdout());
+        }
+        Commands::Config { action } => match action {
+            ConfigAction::Get { key } => match load_config(args)?.config.get(key)? {
+                Some(value) => println!("{}", value),
+                None => std::process::exit(1),
+            },
+            ConfigAction::Set { key, value } => {
+                let mut config = config::load_writable()?;
+                config.set(key, Some(value))?;
+                config::save(&config)?;
+                println!("{} = {}", key.blue(), value.green());
+            }
+            ConfigAction::Unset { key } => {
+                let mut config = config::load_writable()?;
+                config.set(key, None)?;
+                config::save(&config)?;
+                println!("{} reset to its default", key.blue());
+            }
+            ConfigAction::List => print_config(&load_config(args)?),
+        },
+        Commands::Login { force } => {
+            let config = load_config(args)?.config;
+            configure_budget(&config);
+            let provider = config.provider();
+            if !force {
+                match auth::token(provider).await {
+                    Ok(Some(_)) => {
+                        println!("Already logged in. Use --force to sign in again.");
+                        return Ok(());
+                    }
+                    Ok(None) => {}
+                    Err(e) => eprintln!("{} {}", "Stored token is unusable:".yellow(), e),
+                }
+            }
+            if provider.uses_api_key() {
+                let key = read_api_key(provider)?;
+                auth::set_api_key(provider, &key).await?;
+                eprintln!("API key saved for {}", provider.name());
+            } else {
+                login(&config).await?;
+            }
+        }
+        Commands::Logout => {
+            let provider = load_config(args)?.config.provider();
+            if auth::clear_auth_info(provider.auth_key()).await? {
+                println!("Logged out");
+            } else {
+                println!("Not logged in");
+            }
+        }
+        Commands::Run { number } => {
+            let entry = history::entry(*number)?;
+            let config = load_config(args)?.config;
+            let proposal = CommandProposal::literal(&entry.command);
+            if let Review::Done(Some(entry)) = review_and_run(&proposal, &entry.task, args, &config, false).await? {
+                exit_with_outcome(&entry);
+            }
+        }
+        Commands::Retry => {
+            let last = load_last_proposal()?;
+            let config = load_config(args)?.config;
+            if let Review::Done(Some(entry)) = review_and_run(&last.proposal, &last.task, args, &config, false).await? {
+                exit_with_outcome(&entry);
+            }
+        }
+        Commands::Preview { command } => {
+            render_proposal(&CommandProposal::literal(&command.join(" ")), &[], true, false);
+        }
+    }
+    Ok(())
+}
+
+/// Exercise the token refresh and a minimal chat request, printing OK/FAIL
+/// and timing for each step. Never prompts, so it is safe to run in CI.
+async fn test_auth(config: &config::Config) -> bool {
+    let started = Instant::now();
+    match auth::token(config.provider()).await {
+        Ok(Some(_)) => println!("{} token  ({} ms)", "OK  ".green(), started.elapsed().as_millis()),
+        Ok(None) => {
+            println!("{} token: not logged in or token rejected", "FAIL".red());
+            return false;
+        }
+        Err(e) => {
+            println!("{} token: {}", "FAIL".red(), e);
+            return false;
+        }
+    }
+
+    let started = Instant::now();
+    let messages = build_prompt("print hello with echo", "", &PromptOptions::default());
+    let options = RequestOptions::new(config);
+    match within(options.timeout, request_command(&messages, &options, |_| {})).await {
+        Ok(Ok(proposals)) => match proposals.first() {
+            Some(proposal) => {
+                println!("{} chat   ({} ms) {}", "OK  ".green(), started.elapsed().as_millis(), proposal.command.dimmed());
+                true
+            }
+            None => {
+                println!("{} chat: no parseable proposal returned", "FAIL".red());
+                false
+            }
+        },
+        Ok(Err(e)) => {
+            println!("{} chat: {}", "FAIL".red(), e);
+            false
+        }
+        Err(_) => {
+            println!("{} chat: timed out", "FAIL".red());
+            false
+        }
+    }
+}
+
+/// Turn colours off when the output isn't a terminal or `NO_COLOR` is set,
+/// unless `CLICOLOR_FORCE` asks for them anyway. With
+/// `--command-only-stdout` everything coloured goes to stderr, so that is
+/// the stream checked.
+fn configure_colors(args: &Args) {
+    let forced = env::var("CLICOLOR_FORCE").is_ok_and(|v| v != "0");
+    let terminal = if args.command_only_stdout { io::stderr().is_terminal() } else { io::stdout().is_terminal() };
+    if !forced && (!terminal || env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty())) {
+        colored::control::set_override(false);
+    }
+}
+
+/// Load the config and apply overrides given as flags.
+fn load_config(args: &Args) -> Result<config::Resolved, Error> {
+    let mut resolved = config::load_resolved()?;
+    if let Some(model) = &args.model {
+        resolved.apply_flag("model", model)?;
+    }
+    if let Some(timeout) = args.timeout {
+        resolved.apply_flag("timeout", &timeout.to_string())?;
+    }
+    if let Some(temperature) = args.temperature {
+        resolved.apply_flag("temperature", &temperature.to_string())?;
+    }
+    if let Some(max_tokens) = args.max_tokens {
+        resolved.apply_flag("max_tokens", &max_tokens.to_string())?;
+    }
+    if let Some(provider) = args.provider {
+        resolved.apply_flag("provider", provider.name())?;
+    }
+    if let Some(api_base) = &args.api_base {
+        resolved.apply_flag("endpoints.api", api_base)?;
+    }
+    if let Some(shell) = &args.shell {
+        resolved.apply_flag("shell", shell)?;
+    }
+    if args.yes {
+        resolved.apply_flag("auto_approve", "true")?;
+    }
+    if args.highlight {
+        resolved.apply_flag("highlight", "true")?;
+    }
+    Ok(resolved)
+}
+
+fn print_config(resolved: &config::Resolved) {
+    let width = config::KEYS.iter().map(|k| k.len()).max().unwrap_or(0);
+    for key in config::KEYS {
+        let (value, source) = match resolved.config.get(key).ok().flatten() {
+            Some(value) => (value, resolved.source(key).to_string()),
+            // An unset shell falls back to $SHELL, as execution_shell does
+            None if key == "shell" => match env::var("SHELL").ok().filter(|s| !s.is_empty()) {
+                Some(shell) => (shell, "$SHELL".to_string()),
+                None => (default_shell().to_string(), "default".to_string()),
+            },
+            None => ("(unset)".to_string(), resolved.source(key).to_string()),
+        };
+        println!("{} = {}  {}", format!("{:<width$}", key).blue(), value.green(), format!("({})", source).dimmed());
+    }
+}
+
+fn gather_context(shell: &str) -> String {
+    let mut context = format!(
+        "current working directory: {}\nos: {}\nshell: {}",
+        env::current_dir().unwrap_or_default().display(),
+        context::platform(),
+        shell_name(shell)
+    );
+    if let Some(git) = context::git_summary() {
+        context.push('\n');
+        context.push_str(&git);
+    }
+    context
+}
+
+/// Replace the home directory and current username with placeholders so
+/// they are not sent to the API as part of the context.
+fn redact_paths(context: &str) -> String {
+    let mut redacted = context.to_string();
+    if let Ok(home) = env::var("HOME") {
+        let home = home.trim_end_matches('/');
+        if !home.is_empty() {
+            redacted = replace_word(&redacted, home, "~");
+        }
+    }
+    if let Some(user) = env::var("USER").or_else(|_| env::var("LOGNAME")).ok().filter(|u| !u.is_empty()) {
+        redacted = replace_word(&redacted, &user, "<user>");
+    }
+    redacted
+}
+
+/// Replace occurrences of `word` that are not part of a longer identifier.
+fn replace_word(text: &str, word: &str, replacement: &str) -> String {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_' || c == '-' || c == '.';
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find(word) {
+        let before = rest[..pos].chars().next_back();
+        let after = rest[pos + word.len()..].chars().next();
+        result.push_str(&rest[..pos]);
+        if before.is_some_and(is_ident) || after.is_some_and(is_ident) {
+            result.push_str(word);
+        } else {
+            result.push_str(replacement);
+        }
+        rest = &rest[pos + word.len()..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// A user-supplied system prompt replacing the built-in one, with `{task}`
+/// and `{context}` filled in. The reply must still be the JSON object the
+/// built-in prompt asks for, or proposals can't be parsed.
+fn get_prompt_template_path() -> Result<std::path::PathBuf, Error> {
+    Ok(auth::get_config_dir()?.join("prompt.txt"))
+}
+
+fn get_last_proposal_path() -> Result<std::path::PathBuf, Error> {
+    Ok(auth::get_config_dir()?.join("last.json"))
+}
+
+/// The most recent proposal and the task it was for, kept for `th retry`.
+#[derive(Serialize, Deserialize)]
+struct LastProposal {
+    task: String,
+    #[serde(flatten)]
+    proposal: CommandProposal,
+}
+
+/// Remember `proposal` for `th retry`. Failing to write it only matters to
+/// a later retry, so it is logged rather than reported.
+fn save_last_proposal(task: &str, proposal: &CommandProposal) {
+    let last = LastProposal { task: task.to_string(), proposal: proposal.clone() };
+    let written = serde_json::to_string_pretty(&last).map_err(Error::from).and_then(|json| {
+        let path = get_last_proposal_path()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        Ok(std::fs::write(&path, json)?)
+    });
+    if let Err(e) = written {
+        net::log(format!("could not save the last proposal: {}", e));
+    }
+}
+
+fn load_last_proposal() -> Result<LastProposal, Error> {
+    let path = get_last_proposal_path()?;
+    let json = match std::fs::read_to_string(&path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::Other("No previous proposal to retry yet.".to_string()));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    serde_json::from_str(&json).map_err(|e| Error::Parse(format!("Could not parse {}: {}", path.display(), e)))
+}
+
+fn build_prompt(task: &str, context: &str, prompt: &PromptOptions) -> Vec<Message> {
+    let system_message = Message {
+        role: "system".to_string(),
+        content: match &prompt.template {
+            Some(template) => template.replace("{task}", task).replace("{context}", context),
+            None => default_system_prompt(prompt),
+        },
+    };
+
+    // With --no-context there is no section at all, not an empty one
+    let mut content = if context.trim().is_empty() {
+        format!("Task: {}", task)
+    } else {
+        format!("Task: {}\n\nContext:\n{}", task, context)
+    };
+    if let Some(input) = &prompt.input {
+        content.push_str(&format!("\n\nProvided input:\n{}", input));
+    }
+    let user_message = Message { role: "user".to_string(), content };
+
+    vec![system_message, user_message]
+}
+
+fn default_system_prompt(prompt: &PromptOptions) -> String {
+    format!("You are a terminal command planner. Given a user request and project context, respond with ONLY a JSON object containing fields: \"command\", \"explanation\"{}. Do not include any other text, explanations, or formatting. The \"command\" must be a single shell command. Example: {{\"command\": \"ls\", \"explanation\": \"Lists files in the current directory\"}}. {} You must always propose a best-effort command even if information is missing—do not ask follow-up questions. If critical context is unavailable, make a reasonable assumption and mention it in \"explanation\". You cannot execute additional tools yourself; suggest only the command a user should run. Set \"run_in_shell\": true only when the command must change the user's current shell to have any effect (cd, export, source, alias); otherwise omit it. If a safe command truly cannot be produced, return JSON with an empty \"command\" and a short explanation.{}{}{}{}", prompt.summary.fields(), prompt.summary.instruction(), prompt.platform_instruction(), prompt.shell_instruction(), prompt.trash_instruction(), prompt.alternatives_instruction())
+}
+
+/// Parse the `--model-params` argument, which must be a JSON object. Keys that
+/// th manages itself are dropped with a warning.
+fn parse_model_params(raw: &str) -> Result<serde_json::Map<String, serde_json::Value>, String> {
+    let value: serde_json::Value = serde_json::from_str(raw).map_err(|e| e.to_string())?;
+    let serde_json::Value::Object(mut params) = value else {
+        return Err("expected a JSON object".to_string());
+    };
+    for key in MANAGED_PAYLOAD_KEYS {
+        if params.remove(key).is_some() {
+            eprintln!("{} `{}` is managed by th and was ignored in --model-params", "Warning:".yellow(), key);
+        }
+    }
+    Ok(params)
+}
+
+/// Request proposals; there is more than one only with `--alternatives`.
+/// `on_command` is called with the partial command each time more of it
+/// streams in.
+async fn request_command(
+    messages: &[Message],
+    options: &RequestOptions,
+    mut on_command: impl FnMut(&str),
+) -> Result<Vec<CommandProposal>, Error> {
+    let on_content = |content: &str| {
+        if let Some(command) = partial_command(content) {
+            on_command(&command);
+        }
+    };
+    match request_completion(messages, options, on_content).await? {
+        Some(completion) => completion_proposals(&completion),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// The proposals in a finished completion, each marked incomplete if the
+/// stream was cut short.
+fn completion_proposals(completion: &Completion) -> Result<Vec<CommandProposal>, Error> {
+    let mut proposals = parse_streaming_proposals(&completion.body);
+    if proposals.is_empty()
+        && let Some(explanation) = declined_explanation(&completion.body)
+    {
+        return Err(Error::NoSafeCommand(explanation));
+    }
+    for proposal in &mut proposals {
+        proposal.incomplete = completion.interrupted;
+    }
+    Ok(proposals)
+}
+
+/// The raw SSE body of a chat completion.
+struct Completion {
+    body: String,
+    /// The connection dropped before the stream finished.
+    interrupted: bool,
+}
+
+/// Send a streaming chat completion request and return the raw SSE body, or
+/// `None` if the API returned nothing. `on_content`
+/// is called with the message text received so far as each event arrives. If
+/// the connection drops mid-stream, whatever arrived so far is returned with
+/// `interrupted` set, since it may already hold a complete proposal.
+async fn request_completion(
+    messages: &[Message],
+    options: &RequestOptions,
+    on_content: impl FnMut(&str),
+) -> Result<Option<Completion>, Error> {
+    let client = Client::new();
+    let mut token = auth::token(options.provider).await?.ok_or_else(|| login_hint(options.provider))?;
+    let url = options.provider.chat_url(options.api_base.as_deref()).await;
+
+    let mut payload = serde_json::json!({
+        "model": options.model,
+        "messages": messages,
+        "temperature": options.temperature,
+        "max_tokens": options.max_tokens,
+        "stream": true,
+        "stream_options": { "include_usage": true }
+    });
+    if let Some(object) = payload.as_object_mut() {
+        for (key, value) in &options.model_params {
+            object.insert(key.clone(), value.clone());
+        }
+        if let Some(seed) = options.seed {
+            object.insert("seed".to_string(), seed.into());
+        }
+    }
+
+    net::log(format!("POST {}", url));
+    net::log("Authorization: Bearer <redacted>");
+    net::log(format!("{}: {}", net::REQUEST_ID_HEADER, net::request_id()));
+    net::log(format!("payload: {}", serde_json::to_string_pretty(&payload)?));
+
+    let mut attempt = 1;
+    let mut renewed = false;
+    let response = loop {
+        let mut request = client
+            .post(&url)
+            .header("Authorization", format!("Bearer {}", token))
+            .header("Content-Type", "application/json")
+            .header(net::REQUEST_ID_HEADER, net::request_id());
+        if !options.provider.uses_api_key() {
+            request = request
+                .header("Editor-Version", "vscode/1.99.3")
+                .header("Editor-Plugin-Version", "copilot-chat/0.26.7");
+        }
+        let result = request.json(&payload).send_with_retry().await;
+        // A Copilot token can lapse between the check and its use; mint a
+        // new one and try again, but only once
+        if let Ok(response) = &result
+            && response.status() == reqwest::StatusCode::UNAUTHORIZED
+            && !options.provider.uses_api_key()
+            && !renewed
+        {
+            renewed = true;
+            eprintln!("{} the Copilot token was rejected; fetching a new one", "Warning:".yellow());
+            auth::invalidate_access().await?;
+            token = auth::token(options.provider).await?.ok_or_else(|| login_hint(options.provider))?;
+            continue;
+        }
+        // Retry rate limits, server errors and dropped connections; anything
+        // else (bad request, auth) fails straight away
+        let delay = match &result {
+            _ if attempt >= net::MAX_ATTEMPTS => break result?,
+            Ok(response) if net::is_transient_status(response.status()) => net::retry_after(response)
+                .map(Duration::from_secs)
+                .unwrap_or_else(|| net::backoff(attempt)),
+            Err(e) if net::is_transient_error(e) => net::backoff(attempt),
+            _ => break result?,
+        };
+        net::log(format!("attempt {} failed; retrying in {}s", attempt, delay.as_secs()));
+        budget::spend(delay)?;
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    };
+    net::log(format!("HTTP {}", response.status()));
+
+    if response.status().is_success() {
+        read_completion(response.bytes_stream(), on_content).await
+    } else {
+        let status = response.status();
+        let retry_after = net::retry_after(&response);
+        let body = response.text().await.unwrap_or_default();
+        Err(Error::Api { status: Some(status), retry_after, body })
+    }
+}
+
+/// Collect the SSE body from a stream of response chunks. A stream that fails
+/// after some data arrived yields what was read with `interrupted` set.
+async fn read_completion<B: AsRef<[u8]>, E: Into<Error>>(
+    stream: impl futures_util::Stream<Item = Result<B, E>>,
+    mut on_content: impl FnMut(&str),
+) -> Result<Option<Completion>, Error> {
+    let mut buffer = String::new();
+    let mut interrupted = false;
+    let mut content = String::new();
+    let mut lines = sse::LineBuffer::new();
+    let mut stream = std::pin::pin!(stream);
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(_) if !buffer.is_empty() => {
+                interrupted = true;
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        for line in lines.push(chunk.as_ref()) {
+            let delta = stream_content(&line);
+            buffer.push_str(&line);
+            buffer.push('\n');
+            if !delta.is_empty() {
+                content.push_str(&delta);
+                on_content(&content);
+            }
+        }
+    }
+    if let Some(line) = lines.finish() {
+        buffer.push_str(&line);
+    }
+    net::log(format!("raw response:\n{}", buffer.trim_end()));
+    if let Some(usage) = stream_usage(&buffer) {
+        net::record_usage(usage);
+    }
+    if buffer.is_empty() {
+        return Ok(None);
+    }
+    // Errors such as rate limits can arrive as an event inside a 200 stream
+    if stream_content(&buffer).is_empty()
+        && let Some(error) = stream_error(&buffer)
+    {
+        return Err(Error::Api { status: None, retry_after: None, body: error.to_string() });
+    }
+    Ok(Some(Completion { body: buffer, interrupted }))
+}
+
+/// The token counts from the `usage` object that arrives with the final
+/// event when `stream_options.include_usage` is set.
+fn stream_usage(content: &str) -> Option<net::Usage> {
+    content
+        .lines()
+        .rev()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .filter_map(|data| serde_json::from_str::<serde_json::Value>(data).ok())
+        .find_map(|event| {
+            let usage = event.get("usage")?;
+            Some(net::Usage {
+                prompt: usage["prompt_tokens"].as_u64()?,
+                completion: usage["completion_tokens"].as_u64()?,
+            })
+        })
+}
+
+/// The first `error` object in an SSE stream, if any.
+fn stream_error(content: &str) -> Option<serde_json::Value> {
+    content
+        .lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .filter_map(|data| serde_json::from_str::<serde_json::Value>(data).ok())
+        .find_map(|event| event.get("error").cloned())
+}
+
+fn parse_streaming_proposals(content: &str) -> Vec<CommandProposal> {
+    let accumulated_content = stream_content(content);
+    // After accumulating, use extract_json to find the JSON in the content
+    let Some(json) = extract_json(&accumulated_content) else {
+        return Vec::new();
+    };
+    // With --alternatives the candidates come wrapped in an array
+    match json.get("alternatives").and_then(|v| v.as_array()) {
+        Some(alternatives) => alternatives.iter().filter_map(proposal_from_json).collect(),
+        None => proposal_from_json(&json).into_iter().collect(),
+    }
+}
+
+/// The model's explanation when it parsed as JSON but deliberately left the
+/// command empty, as the system prompt allows when no safe command exists.
+fn declined_explanation(content: &str) -> Option<String> {
+    let json = extract_json(&stream_content(content))?;
+    let reply = match json.get("alternatives").and_then(|v| v.as_array()) {
+        Some(alternatives) => alternatives.first()?,
+        None => &json,
+    };
+    if !reply.get("command")?.as_str()?.trim().is_empty() {
+        return None;
+    }
+    let explanation = reply.get("explanation").and_then(|v| v.as_str()).map(str::trim).unwrap_or_default();
+    Some(if explanation.is_empty() { "the model gave no reason".to_string() } else { explanation.to_string() })
+}
+
+fn proposal_from_json(json: &serde_json::Value) -> Option<CommandProposal> {
+    let command = json.get("command")?.as_str()?.trim().to_string();
+    let explanation = json.get("explanation").and_then(|v| v.as_str()).map(|s| s.trim().to_string());
+    let summary = json.get("summary").and_then(|v| v.as_str()).map(|s| s.trim().to_string());
+    let run_in_shell = json.get("run_in_shell").and_then(|v| v.as_bool()).unwrap_or(false);
+    // A NUL byte can't be passed to a shell and only turns up in garbage
+    if command.is_empty() || command.contains('\0') {
+        return None;
+    }
+    Some(CommandProposal { command, explanation, summary, run_in_shell, incomplete: false })
+}
+
+/// Concatenate the content pieces of an SSE chat completion stream.
+fn stream_content(content: &str) -> String {
+    let mut accumulated_content = String::new();
+    // Handle SSE format: split by "data: " and parse each JSON
+    for line in content.lines() {
+        if let Some(data) = line.strip_prefix("data: ") {
+            if data == "[DONE]" {
+                continue;
+            }
+            // Most providers stream token deltas, but some send the whole
+            // message in one event under `message` instead of `delta`
+            if let Ok(json_value) = serde_json::from_str::<serde_json::Value>(data) {
+                let choice = &json_value["choices"][0];
+                if let Some(content_str) = choice["delta"]["content"].as_str() {
+                    accumulated_content.push_str(content_str);
+                } else if let Some(content_str) = choice["message"]["content"].as_str() {
+                    accumulated_content.push_str(content_str);
+                }
+            }
+        }
+    }
+    accumulated_content
+}
+
+/// The value of the `command` field in a possibly unfinished JSON reply, for
+/// showing progress while it streams in.
+fn partial_command(content: &str) -> Option<String> {
+    let rest = &content[content.find("\"command\"")? + "\"command\"".len()..];
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start().strip_prefix('"')?;
+    let mut command = String::new();
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => match chars.next() {
+                Some('n') => command.push(' '),
+                Some('t') => command.push(' '),
+                Some(other) => command.push(other),
+                None => break,
+            },
+            _ => command.push(c),
+        }
+    }
+    Some(command)
+}
+
+fn extract_json(content: &str) -> Option<serde_json::Value> {
+    let trimmed = strip_code_fence(content.trim());
+    // Try direct parsing first
+    if let Ok(json) = serde_json::from_str(trimmed) {
+        return Some(json);
+    }
+    // Try adding } at the end, in case the JSON is truncated
+    let with_brace = trimmed.to_string() + "}";
+    if let Ok(json) = serde_json::from_str(&with_brace) {
+        return Some(json);
+    }
+    // Fall back to the first balanced object in the surrounding prose
+    trimmed
+        .match_indices('{')
+        .filter_map(|(start, _)| balanced_object(&trimmed[start..]))
+        .find_map(|object| serde_json::from_str(object).ok())
+}
+
+/// Unwrap a Markdown code fence such as ```` ```json ... ``` ````, leaving
+/// anything else untouched.
+fn strip_code_fence(content: &str) -> &str {
+    let Some(rest) = content.strip_prefix("```") else {
+        return content;
+    };
+    // Drop the info string (`json`, `JSON`, ...) on the opening line
+    let body = rest.split_once('\n').map_or("", |(_, body)| body);
+    body.trim_end().strip_suffix("```").unwrap_or(body).trim()
+}
+
+/// The object starting at the `{` that opens `text`, up to its matching `}`.
+/// Braces inside string literals, such as the `{}` in `find . -exec {} \;`,
+/// don't count.
+fn balanced_object(text: &str) -> Option<&str> {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => {
+                depth = depth.checked_sub(1)?;
+                if depth == 0 {
+                    return Some(&text[..=i]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+const DEFAULT_SPINNER_FRAMES: &str = ":⁖⁘⁛⁙⁛⁘⁖";
+/// Frames used when the terminal may not render the default glyphs.
+const ASCII_SPINNER_FRAMES: &str = "|/-\\";
+const DEFAULT_SPINNER_INTERVAL_MS: u64 = 140;
+
+struct Spinner {
+    running: Arc<Mutex<bool>>,
+    label: Arc<Mutex<String>>,
+    handle: Option<thread::JoinHandle<()>>,
+    to_stderr: bool,
+}
+
+impl Spinner {
+    fn new(label: String) -> Self {
+        Self::start(label, false)
+    }
+
+    /// A spinner drawn on stderr, for modes where stdout must stay clean.
+    fn on_stderr(label: String) -> Self {
+        Self::start(label, true)
+    }
+
+    fn start(label: String, to_stderr: bool) -> Self {
+        let is_terminal = if to_stderr { io::stderr().is_terminal() } else { io::stdout().is_terminal() };
+        let running = Arc::new(Mutex::new(is_terminal));
+        let running_clone = running.clone();
+        let label = Arc::new(Mutex::new(label));
+        let label_clone = label.clone();
+        // Animating into a pipe or log file would only leave garbage behind
+        if !is_terminal {
+            return Self { running, label, handle: None, to_stderr };
+        }
+        let (frames, interval) = SPINNER_STYLE.get().cloned().unwrap_or_else(|| {
+            (DEFAULT_SPINNER_FRAMES.chars().collect(), Duration::from_millis(DEFAULT_SPINNER_INTERVAL_MS))
+        });
+        let handle = thread::spawn(move || {
+            let mut index = 0;
+            while *running_clone.lock().unwrap() {
+                // Keep the label on one line so stopping can clear it completely
+                let label = truncate(&label_clone.lock().unwrap(), terminal_width().saturating_sub(3));
+                let mut out = spinner_output(to_stderr);
+                write!(out, "\r{} {}\x1b[K", format!("{}", frames[index]).yellow(), label).unwrap();
+                out.flush().unwrap();
+                thread::sleep(interval);
+                index = (index + 1) % frames.len();
+            }
+        });
+        Self { running, label, handle: Some(handle), to_stderr }
+    }
+
+    /// Replace the text shown next to the spinner from the next frame on.
+    fn set_label(&self, label: String) {
+        *self.label.lock().unwrap() = label;
+    }
+
+    fn stop(&mut self) {
+        *self.running.lock().unwrap() = false;
+        if let Some(handle) = self.handle.take() {
+            handle.join().unwrap();
+            let mut out = spinner_output(self.to_stderr);
+            write!(out, "\r\x1b[K").unwrap();
+            out.flush().unwrap();
+        }
+    }
+}
+
+fn spinner_output(to_stderr: bool) -> Box<dyn Write> {
+    if to_stderr {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        if *self.running.lock().unwrap() {
+            self.stop();
+        }
+    }
+}
+
+/// The user's answer to the approval prompt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Approval {
+    Run,
+    Edit,
+    Regenerate,
+    Cancel,
+}
+
+/// Ask before executing. With `strict`, used when the proposal raised
+/// warnings, only the full word "yes" counts as approval. With `regenerate`,
+/// "r" asks for a different proposal.
+async fn request_approval(strict: bool, limit: Option<Duration>, regenerate: bool) -> Approval {
+    if strict {
+        let other = if regenerate { "e to edit, r for another" } else { "e to edit" };
+        print!("{} Type 'yes' to execute this command (or {}): ", "  ->".red(), other);
+    } else {
+        let choices = if regenerate { "y/N/e/r" } else { "y/N/e" };
+        print!("{} Execute this command? ({}): ", "  ->".yellow(), choices);
+    }
+    io::stdout().flush().unwrap();
+    let Some(input) = read_answer(limit) else {
+        println!();
+        println!("{}", "Timed out, not executing".yellow());
+        return Approval::Cancel;
+    };
+    let answer = input.trim().to_lowercase();
+    let approved = if strict { answer == "yes" } else { answer.starts_with('y') };
+    if approved {
+        Approval::Run
+    } else if answer == "e" || answer == "edit" {
+        Approval::Edit
+    } else if regenerate && (answer == "r" || answer == "regenerate") {
+        Approval::Regenerate
+    } else {
+        Approval::Cancel
+    }
+}
+
+/// Read a line from stdin, giving up after `limit`, so an unattended prompt
+/// can't hold the process forever. The terminal stays in cooked mode
+/// throughout, so there is nothing to restore.
+fn read_answer(limit: Option<Duration>) -> Option<String> {
+    read_line_within(limit).map(Result::unwrap_or_default)
+}
+
+/// Read a line from stdin, waiting as long as it takes. An empty line means
+/// the input ended.
+fn read_line() -> io::Result<String> {
+    read_line_within(None).unwrap_or_else(|| Err(io::Error::other("stdin reader stopped")))
+}
+
+/// The thread that reads stdin for every prompt, one line per request.
+struct StdinReader {
+    requests: std::sync::mpsc::Sender<()>,
+    lines: std::sync::mpsc::Receiver<io::Result<String>>,
+    /// A read was requested but its line hasn't been collected yet.
+    pending: bool,
+}
+
+static STDIN_READER: OnceLock<Mutex<StdinReader>> = OnceLock::new();
+
+/// Read a line through the shared stdin thread, or `None` once `limit`
+/// passes. A read that times out stays pending and answers the next prompt,
+/// rather than being left on an orphaned thread that would swallow the
+/// user's next line.
+fn read_line_within(limit: Option<Duration>) -> Option<io::Result<String>> {
+    let reader = STDIN_READER.get_or_init(|| {
+        let (requests, pending_requests) = std::sync::mpsc::channel::<()>();
+        let (sender, lines) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            for () in pending_requests {
+                let mut input = String::new();
+                let line = io::stdin().read_line(&mut input).map(|_| input);
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Mutex::new(StdinReader { requests, lines, pending: false })
+    });
+    let mut reader = reader.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+    if !reader.pending {
+        reader.requests.send(()).ok()?;
+        reader.pending = true;
+    }
+    let line = match limit {
+        Some(limit) => reader.lines.recv_timeout(limit).ok()?,
+        None => reader.lines.recv().ok()?,
+    };
+    reader.pending = false;
+    Some(line)
+}
+
+/// Let the user change the command in `$VISUAL`/`$EDITOR`, or on a prompt
+/// line when neither is set. An empty result keeps the command unchanged.
+fn edit_command(command: &str) -> Result<String, Error> {
+    let editor = env::var("VISUAL").or_else(|_| env::var("EDITOR")).ok().filter(|e| !e.trim().is_empty());
+    let edited = match editor {
+        Some(editor) => {
+            let path = env::temp_dir().join(format!("th-command-{}.sh", std::process::id()));
+            std::fs::write(&path, format!("{}\n", command))?;
+            // Go through sh so an EDITOR with arguments, such as `code -w`, works
+            let status = Command::new("sh").arg("-c").arg(format!("{} \"$1\"", editor)).arg("sh").arg(&path).status();
+            let edited = std::fs::read_to_string(&path);
+            let _ = std::fs::remove_file(&path);
+            if !status?.success() {
+                return Err(format!("{} exited with an error", editor).into());
+            }
+            edited?
+        }
+        None => {
+            print!("{} New command: ", "  ->".yellow());
+            io::stdout().flush()?;
+            read_line()?
+        }
+    };
+    let edited = edited.trim();
+    Ok(if edited.is_empty() { command.to_string() } else { edited.to_string() })
+}
+
+/// The trash tool to use instead of `rm`, if `prefer_trash` is enabled and
+/// one is installed.
+fn trash_command(config: &config::Config) -> Option<String> {
+    if !config.prefer_trash() {
+        return None;
+    }
+    if find_in_path("trash") {
+        Some("trash".to_string())
+    } else if find_in_path("trash-put") {
+        Some("trash-put".to_string())
+    } else if find_in_path("gio") {
+        Some("gio trash".to_string())
+    } else {
+        None
+    }
+}
+
+fn find_in_path(binary: &str) -> bool {
+    env::var_os("PATH").is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(binary).is_file()))
+}
+
+/// Rewrite every `rm` invocation in `command` to use `trash`, dropping rm's
+/// own flags and leaving the rest of the text as it was. Returns `None` when
+/// the command doesn't call `rm`.
+fn substitute_trash(command: &str, trash: &str) -> Option<String> {
+    let words = command_words(command);
+    let mut rewritten = String::with_capacity(command.len());
+    let mut copied = 0;
+    let mut i = 0;
+    while i < words.len() {
+        let (word, at_command_start) = words[i].clone();
+        i += 1;
+        if !at_command_start || &command[word.clone()] != "rm" {
+            continue;
+        }
+        let mut end = word.end;
+        while let Some((flag, false)) = words.get(i).cloned()
+            && command[flag.clone()].starts_with('-')
+        {
+            end = flag.end;
+            i += 1;
+            if &command[flag] == "--" {
+                break;
+            }
+        }
+        rewritten.push_str(&command[copied..word.start]);
+        rewritten.push_str(trash);
+        copied = end;
+    }
+    (copied > 0).then(|| rewritten + &command[copied..])
+}
+
+/// Words that run the word after them as a command.
+const COMMAND_PREFIXES: [&str; 7] = ["sudo", "xargs", "then", "do", "else", "nohup", "time"];
+
+/// The byte ranges of the words in `command`, each with whether it is where a
+/// command name would go: the start of the text or of a statement, or right
+/// after a prefix such as `sudo`. Quoted text stays inside its word.
+fn command_words(command: &str) -> Vec<(std::ops::Range<usize>, bool)> {
+    let mut words = Vec::new();
+    let mut word: Option<(usize, bool)> = None;
+    let mut at_command_start = true;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in command.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        if let Some(open) = quote {
+            if c == open {
+                quote = None;
+            }
+            continue;
+        }
+        let separator = matches!(c, '\n' | ';' | '|' | '&' | '(' | ')' | '`');
+        if separator || c.is_whitespace() {
+            if let Some((start, starts_command)) = word.take() {
+                at_command_start = COMMAND_PREFIXES.contains(&&command[start..i]);
+                words.push((start..i, starts_command));
+            }
+            if separator {
+                at_command_start = true;
+            }
+            continue;
+        }
+        word.get_or_insert((i, at_command_start));
+        match c {
+            '\'' | '"' => quote = Some(c),
+            '\\' => escaped = true,
+            _ => {}
+        }
+    }
+    if let Some((start, starts_command)) = word {
+        words.push((start..command.len(), starts_command));
+    }
+    words
+}
+
+/// Put the command on the clipboard for `--copy`.
+fn copy_proposal(proposal: &CommandProposal) -> Result<(), Error> {
+    copy_to_clipboard(&proposal.command)?;
+    println!("{}", "Copied to clipboard".green());
+    Ok(())
+}
+
+/// Copy `text` with the first clipboard tool that suits the session.
+fn copy_to_clipboard(text: &str) -> Result<(), String> {
+    let candidates: &[&[&str]] = if cfg!(target_os = "macos") {
+        &[&["pbcopy"]]
+    } else if cfg!(windows) {
+        &[&["clip"]]
+    } else if env::var_os("WAYLAND_DISPLAY").is_some() {
+        &[&["wl-copy"], &["xclip", "-selection", "clipboard"], &["xsel", "--clipboard", "--input"]]
+    } else if env::var_os("DISPLAY").is_some() {
+        &[&["xclip", "-selection", "clipboard"], &["xsel", "--clipboard", "--input"]]
+    } else {
+        return Err("No clipboard available: no graphical session was found.".to_string());
+    };
+    let Some((program, args)) = candidates.iter().find(|c| find_in_path(c[0])).and_then(|c| c.split_first()) else {
+        let names: Vec<&str> = candidates.iter().map(|c| c[0]).collect();
+        return Err(format!("No clipboard tool found; install one of: {}", names.join(", ")));
+    };
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Could not run {}: {}", program, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes()).map_err(|e| format!("Could not write to {}: {}", program, e))?;
+    }
+    match child.wait() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("{} failed ({})", program, status)),
+        Err(e) => Err(format!("Could not run {}: {}", program, e)),
+    }
+}
+
+/// Show the trash rewrite of a command and ask whether to use it instead.
+fn offer_trash_substitution(original: &str, rewritten: &str) -> bool {
+    show_trash_substitution(original, rewritten);
+    print!("{} Use the trash version instead? (Y/n): ", "  ->".yellow());
+    io::stdout().flush().unwrap();
+    let input = read_line().unwrap_or_default();
+    !input.trim().to_lowercase().starts_with('n')
+}
+
+/// Print a command next to its trash rewrite.
+fn show_trash_substitution(original: &str, rewritten: &str) {
+    println!("  {} {}", "original:".blue(), original.dimmed());
+    println!("  {} {}", "trash:".blue(), rewritten.green());
+}
+
+/// Prompt for each statement in turn, showing its own warnings. Returns the
+/// approved statements joined into one script, or `None` if none were
+/// approved or the user quit.
+fn confirm_statements(statements: &[String], config: &config::Config) -> Option<String> {
+    let mut approved = Vec::new();
+    for (i, statement) in statements.iter().enumerate() {
+        println!("  {} {}", format!("[{}/{}]", i + 1, statements.len()).blue(), statement.green());
+        let warnings = command_warnings(statement, config);
+        render_warnings(&warnings);
+        if warnings.is_empty() {
+            print!("{} Run this statement? (y/N/q): ", "  ->".yellow());
+        } else {
+            print!("{} Type 'yes' to run this statement (or q to quit): ", "  ->".red());
+        }
+        io::stdout().flush().unwrap();
+        let input = read_line().unwrap_or_default();
+        let answer = input.trim().to_lowercase();
+        if answer == "q" {
+            return None;
+        }
+        let accepted = if warnings.is_empty() { answer.starts_with('y') } else { answer == "yes" };
+        if accepted {
+            approved.push(statement.clone());
+        }
+    }
+    (!approved.is_empty()).then(|| approved.join("\n"))
+}
+
+/// Whether `command` is one statement: a single pipeline without newlines,
+/// `;`, `&&` or `||`. The operator check is textual, so a quoted `&&` counts.
+fn is_single_statement(command: &str) -> bool {
+    split_statements(command).len() <= 1 && !command.contains("&&") && !command.contains("||")
+}
+
+/// Split a script into top-level statements on newlines and `;`, ignoring
+/// separators inside quotes, `$(...)`, `(...)` and `{...}`. Scripts with
+/// heredocs are returned whole since their bodies can't be split safely.
+fn split_statements(script: &str) -> Vec<String> {
+    if script.contains("<<") {
+        return vec![script.trim().to_string()];
+    }
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0usize;
+    let mut quote: Option<char> = None;
+    let mut chars = script.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => {
+                current.push(c);
+                match chars.next() {
+                    // A line continuation joins the next line onto this statement
+                    Some('\n') => {
+                        current.pop();
+                        current.push(' ');
+                    }
+                    Some(next) => current.push(next),
+                    None => {}
+                }
+                continue;
+            }
+            (Some('"'), '"') => quote = None,
+            (Some('"'), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '(' | '{') => depth += 1,
+            (None, ')' | '}') => depth = depth.saturating_sub(1),
+            (None, '\n' | ';') if depth == 0 => {
+                // Keep `;;` (case arms) intact
+                if c == ';' && chars.peek() == Some(&';') {
+                    current.push(c);
+                    current.push(chars.next().unwrap());
+                    continue;
+                }
+                push_statement(&mut statements, &current);
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    push_statement(&mut statements, &current);
+    statements
+}
+
+fn push_statement(statements: &mut Vec<String>, statement: &str) {
+    let statement = statement.trim();
+    if !statement.is_empty() && !statement.starts_with('#') {
+        statements.push(statement.to_string());
+    }
+}
+
+/// Ask for an optional reason after a rejection; an empty answer skips it.
+fn request_rejection_reason() -> Option<String> {
+    print!("{} Why not? (Enter to skip): ", "  ->".yellow());
+    io::stdout().flush().unwrap();
+    let input = read_line().ok()?;
+    let reason = input.trim();
+    (!reason.is_empty()).then(|| reason.to_string())
+}
+
+/// Append to the history log. A failure to record is reported but not fatal.
+fn record_history(entry: &history::HistoryEntry) {
+    if let Err(e) = history::append(entry) {
+        eprintln!("{} could not write history: {}", "Warning:".yellow(), e);
+    }
+}
+
+/// How and where an approved command is run.
+#[derive(Debug)]
+struct ExecOptions {
+    container: Option<Container>,
+    /// Shell that runs the command outside a container.
+    shell: String,
+    /// Capture the output so it can be sent back if the command fails.
+    capture: bool,
+    /// Kill the command if it runs longer than this.
+    timeout: Option<Duration>,
+}
+
+/// A container to run the command in, with the working directory mounted at `/work`.
+#[derive(Debug)]
+struct Container {
+    runtime: &'static str,
+    image: String,
+    /// Unique name given to the container so it can be killed on timeout.
+    name: String,
+}
+
+impl Container {
+    /// Stop the container. Killing the `docker run` client alone leaves it
+    /// running in the background.
+    fn kill(&self) {
+        let _ = Command::new(self.runtime)
+            .args(["kill", &self.name])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    }
+}
+
+impl ExecOptions {
+    fn from_args(args: &Args, config: &config::Config) -> Result<Self, String> {
+        let container = match &args.in_container {
+            Some(image) => {
+                let runtime = ["docker", "podman"]
+                    .into_iter()
+                    .find(|runtime| find_in_path(runtime))
+                    .ok_or("--in-container needs docker or podman on PATH")?;
+                Some(Container { runtime, image: image.clone(), name: format!("th-{}", uuid::Uuid::new_v4()) })
+            }
+            None => None,
+        };
+        Ok(Self {
+            container,
+            shell: execution_shell(args, config),
+            capture: args.iterate,
+            timeout: args.exec_timeout.map(Duration::from_secs),
+        })
+    }
+}
+
+/// The shell that runs commands on the host: `--shell`, then the `shell`
+/// config key, then `$SHELL`, then the platform default.
+fn execution_shell(args: &Args, config: &config::Config) -> String {
+    args.shell
+        .clone()
+        .or_else(|| config.shell.clone())
+        .or_else(|| env::var("SHELL").ok().filter(|s| !s.is_empty()))
+        .unwrap_or_else(|| default_shell().to_string())
+}
+
+/// The shell used when neither the flags, the config nor `$SHELL` name one.
+fn default_shell() -> &'static str {
+    if cfg!(windows) { "powershell" } else { "bash" }
+}
+
+/// The shell the proposed command will actually run in, accounting for
+/// `--in-container`.
+fn target_shell(args: &Args, config: &config::Config) -> String {
+    if args.in_container.is_some() {
+        "sh".to_string()
+    } else {
+        execution_shell(args, config)
+    }
+}
+
+/// The shell's name without its directory or `.exe` suffix.
+fn shell_name(shell: &str) -> &str {
+    std::path::Path::new(shell).file_stem().and_then(|n| n.to_str()).unwrap_or(shell)
+}
+
+/// Arguments that make `shell` run the next argument as a command string.
+/// POSIX-style shells run it as a login shell so profile setup applies.
+fn shell_command_flag(shell: &str) -> &'static str {
+    match shell_name(shell).to_lowercase().as_str() {
+        "bash" | "zsh" | "ksh" | "sh" | "dash" => "-lc",
+        "powershell" | "pwsh" => "-Command",
+        "cmd" => "/C",
+        _ => "-c",
+    }
+}
+
+fn build_command(command: &str, exec: &ExecOptions) -> Result<Command, Error> {
+    let cwd = env::current_dir()?;
+    let mut process = match &exec.container {
+        Some(container) => {
+            let mut process = Command::new(container.runtime);
+            process
+                .args(["run", "--rm", "-i", "--name", &container.name, "-v"])
+                .arg(format!("{}:/work", cwd.display()))
+                .args(["-w", "/work", &container.image, "sh", "-c", command]);
+            process
+        }
+        None => {
+            let mut process = Command::new(&exec.shell);
+            process.arg(shell_command_flag(&exec.shell)).arg(command);
+            process
+        }
+    };
+    process.current_dir(cwd);
+    Ok(process)
+}
+
+/// The full invocation for display, so the user sees exactly what will run.
+fn describe_invocation(command: &str, exec: &ExecOptions) -> Option<String> {
+    let container = exec.container.as_ref()?;
+    Some(format!(
+        "{} run --rm -i --name {} -v \"$PWD\":/work -w /work {} sh -c '{}'",
+        container.runtime,
+        container.name,
+        container.image,
+        command.replace('\'', "'\\''")
+    ))
+}
+
+/// Why an approved command didn't succeed.
+#[derive(Debug)]
+struct CommandFailure {
+    message: String,
+    /// The command's exit code; `None` if it never started or was killed by
+    /// a signal.
+    code: Option<i32>,
+    /// Combined stdout and stderr, when `ExecOptions::capture` is set.
+    output: Option<String>,
+}
+
+async fn execute_command(command: &str, exec: &ExecOptions) -> Result<(), CommandFailure> {
+    let failure = |message: String| CommandFailure { message, code: None, output: None };
+    let mut process = build_command(command, exec).map_err(|e| failure(e.to_string()))?;
+    let spawn_error = |e: io::Error| match e.kind() {
+        io::ErrorKind::NotFound if exec.container.is_none() => failure(format!("Shell `{}` not found", exec.shell)),
+        _ => failure(e.to_string()),
+    };
+
+    let timed_out = |limit: Duration| {
+        if let Some(container) = &exec.container {
+            container.kill();
+        }
+        CommandFailure {
+            code: Some(EXEC_TIMEOUT_CODE),
+            ..failure(format!("Command timed out after {}s and was killed", limit.as_secs()))
+        }
+    };
+
+    if !exec.capture {
+        let status = match exec.timeout {
+            Some(limit) => {
+                let child = spawn_killable(&mut process).map_err(spawn_error)?;
+                wait_within(child, limit).map_err(|e| failure(e.to_string()))?.ok_or_else(|| timed_out(limit))?
+            }
+            None => process.status().map_err(spawn_error)?,
+        };
+        if status.success() {
+            return Ok(());
+        }
+        return Err(CommandFailure { code: status.code(), ..failure(format!("Command exited with code {:?}", status.code())) });
+    }
+
+    let output = match exec.timeout {
+        Some(limit) => {
+            process.stdout(Stdio::piped()).stderr(Stdio::piped());
+            let mut child = spawn_killable(&mut process).map_err(spawn_error)?;
+            // Drain both pipes while waiting so a chatty command can't stall
+            let stdout = drain(child.stdout.take());
+            let stderr = drain(child.stderr.take());
+            let status = wait_within(child, limit).map_err(|e| failure(e.to_string()))?;
+            let (stdout, stderr) = (stdout.join().unwrap_or_default(), stderr.join().unwrap_or_default());
+            let Some(status) = status else {
+                io::stdout().write_all(&stdout).ok();
+                io::stderr().write_all(&stderr).ok();
+                return Err(timed_out(limit));
+            };
+            std::process::Output { status, stdout, stderr }
+        }
+        None => process.stdin(Stdio::inherit()).output().map_err(spawn_error)?,
+    };
+    io::stdout().write_all(&output.stdout).ok();
+    io::stderr().write_all(&output.stderr).ok();
+    if output.status.success() {
+        return Ok(());
+    }
+    let mut combined = String::from_utf8_lossy(&output.stdout).into_owned();
+    combined.push_str(&String::from_utf8_lossy(&output.stderr));
+    Err(CommandFailure {
+        message: format!("Command exited with code {:?}", output.status.code()),
+        code: output.status.code(),
+        output: Some(context::truncate_bytes(&combined, MAX_REPAIR_OUTPUT_BYTES)),
+    })
+}
+
+/// Exit code reported for a command killed by `--exec-timeout`, as with
+/// timeout(1).
+const EXEC_TIMEOUT_CODE: i32 = 124;
+
+/// Start `process` so that `wait_within` can kill it together with any
+/// pipeline or children it starts: on Unix it gets its own process group.
+/// Being outside the terminal's foreground group, it gets no terminal input.
+fn spawn_killable(process: &mut Command) -> io::Result<std::process::Child> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        process.process_group(0);
+    }
+    process.stdin(Stdio::null()).spawn()
+}
+
+/// Read `pipe` to the end on a separate thread.
+fn drain(pipe: Option<impl io::Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        bytes
+    })
+}
+
+/// Wait for `child` for up to `limit`. If it is still running by then, kill
+/// it and everything in its process group and return `None`.
+fn wait_within(mut child: std::process::Child, limit: Duration) -> io::Result<Option<std::process::ExitStatus>> {
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if started.elapsed() >= limit {
+            #[cfg(unix)]
+            // SAFETY: kill(2) with a negative pid only signals the process
+            // group that `spawn_killable` created for this child.
+            unsafe {
+                libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+            }
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Write the proposal as an executable bash script, with the explanation and
+/// summary as a comment header.
+fn save_proposal(proposal: &CommandProposal, path: &std::path::Path) -> io::Result<()> {
+    let mut script = String::from("#!/usr/bin/env bash\n");
+    for note in [&proposal.explanation, &proposal.summary].into_iter().flatten() {
+        for line in note.lines() {
+            script.push_str(&format!("# {}\n", line));
+        }
+    }
+    script.push_str(&proposal.command);
+    script.push('\n');
+    std::fs::write(path, script)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+/// Signal that a command has finished, if enabled in the config. Failures
+/// here are reported but never affect the outcome of the run.
+fn notify_completion(config: &config::Config) {
+    if !config.bell_on_complete() {
+        return;
+    }
+    match &config.sound_command {
+        Some(sound) => {
+            if let Err(e) = Command::new("sh").arg("-c").arg(sound).status() {
+                eprintln!("{} sound command failed: {}", "Warning:".yellow(), e);
+            }
+        }
+        None => {
+            print!("\x07");
+            io::stdout().flush().unwrap();
+        }
+    }
+}
+
+/// Reasons a proposal deserves a closer look before it is approved.
+fn proposal_warnings(proposal: &CommandProposal, config: &config::Config) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if proposal.incomplete {
+        warnings.push("the connection dropped mid-response; the command may be incomplete".to_string());
+    }
+    warnings.extend(command_warnings(&proposal.command, config));
+    warnings
+}
+
+/// Warnings that depend only on the command text.
+fn command_warnings(command: &str, config: &config::Config) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let length = command.chars().count();
+    if length > config.max_command_length() {
+        warnings.push(format!(
+            "command is {} characters long (limit {}); review it carefully",
+            length,
+            config.max_command_length()
+        ));
+    }
+    let lines = command.lines().filter(|l| !l.trim().is_empty()).count();
+    if lines > 1 {
+        warnings.push(format!("command spans {} lines; every line will run", lines));
+    }
+    if let risk::Risk::Dangerous(reason) = risk::risk_level(command, config.dangerous_patterns()) {
+        warnings.push(format!("potentially destructive: {}", reason));
+    }
+    warnings
+}
+
+/// Most values listed per word by `--preview-expand`.
+const MAX_EXPANSION_VALUES: usize = 20;
+
+fn render_expansions(command: &str) {
+    let expansions = expand::expansions(command, MAX_EXPANSION_VALUES);
+    if expansions.is_empty() {
+        println!("  {} {}", "expands:".blue(), "nothing to expand".dimmed());
+        println!();
+        return;
+    }
+    println!("  {}", "expands:".blue());
+    for expansion in expansions {
+        println!("    {}", expansion.word.green());
+        for value in &expansion.values {
+            println!("      {}", value);
+        }
+        if expansion.more > 0 {
+            println!("      {}", format!("… and {} more", expansion.more).dimmed());
+        }
+        if let Some(note) = &expansion.note {
+            println!("      {}", note.yellow());
+        }
+    }
+    println!();
+}
+
+fn render_warnings(warnings: &[String]) {
+    for warning in warnings {
+        println!("  {} {}", "warning:".red(), warning.red());
+    }
+}
+
+/// With `pager`, a reason and summary too tall for the terminal are shown
+/// through `$PAGER` rather than scrolling the command out of view.
+fn render_proposal(proposal: &CommandProposal, warnings: &[String], highlight: bool, pager: bool) {
+    let width = terminal_width();
+    // Long lines wrap at spaces, each wrapped piece ending in a `\` so the
+    // command still reads as one
+    let indent = "  command: ".len();
+    let mut label = "command:".blue().to_string();
+    for line in proposal.command.split('\n') {
+        let pieces = wrap(line, width.saturating_sub(indent + 2));
+        for (i, piece) in pieces.iter().enumerate() {
+            let text = if highlight { highlight::highlight(piece) } else { piece.green().to_string() };
+            let marker = if i + 1 < pieces.len() { " \\".dimmed().to_string() } else { String::new() };
+            println!("  {} {}{}", label, text, marker);
+            label = " ".repeat("command:".len());
+        }
+    }
+
+    let mut notes = String::new();
+    if let Some(explanation) = &proposal.explanation {
+        notes += &labelled("reason:", explanation, width);
+    }
+    if let Some(summary) = &proposal.summary {
+        notes += &labelled("summary:", summary, width);
+    }
+    let height = notes.lines().count();
+    let paged = pager && io::stdout().is_terminal() && height + 1 > terminal_height() && page(&notes);
+    if !paged {
+        print!("{}", notes);
+    }
+
+    render_warnings(warnings);
+
+    if runs_in_shell(proposal) && env::var_os(shell_init::EVAL_FILE_ENV).is_none() {
+        println!(
+            "  {} {}",
+            "note:".yellow(),
+            "this runs in a subshell, so its effect won't persist in your shell. Run it yourself, or set up `th shell-init`.".yellow()
+        );
+    }
+
+    println!();
+}
+
+/// Whether the proposal has to run in the calling shell to have any effect.
+fn runs_in_shell(proposal: &CommandProposal) -> bool {
+    proposal.run_in_shell || is_cd_command(&proposal.command)
+}
+
+/// Whether the command starts by changing directory, which has no lasting
+/// effect when run through `execute_command`'s subshell.
+fn is_cd_command(command: &str) -> bool {
+    let first = command.split_whitespace().next().unwrap_or("");
+    matches!(first, "cd" | "pushd" | "popd")
+}
+
+/// Write the explanation, summary and warnings to stderr, leaving stdout for
+/// the command.
+fn render_proposal_notes(proposal: &CommandProposal, warnings: &[String]) {
+    if let Some(explanation) = &proposal.explanation {
+        eprintln!("  {} {}", "reason:".blue(), explanation.dimmed());
+    }
+
+    if let Some(summary) = &proposal.summary {
+        eprintln!("  {} {}", "summary:".blue(), summary.dimmed());
+    }
+
+    for warning in warnings {
+        eprintln!("  {} {}", "warning:".red(), warning.red());
+    }
+}
+
+/// Render proposals as an aligned `#  command  reason` table that fits the
+/// terminal width, truncating the longest columns when needed.
+fn render_proposal_table(proposals: &[CommandProposal]) {
+    let width = terminal_width();
+    let index_width = proposals.len().to_string().len().max(1);
+    let command_width = proposals
+        .iter()
+        .map(|p| p.command.chars().count())
+        .max()
+        .unwrap_or(0)
+        .clamp("command".len(), (width / 2).max("command".len()));
+    let reason_width = width.saturating_sub(index_width + command_width + 6).max("reason".len());
+
+    println!(
+        "  {}  {}  {}",
+        format!("{:>index_width$}", "#").blue(),
+        format!("{:<command_width$}", "command").blue(),
+        "reason".blue()
+    );
+    for (i, proposal) in proposals.iter().enumerate() {
+        let reason = proposal.explanation.as_deref().unwrap_or("");
+        println!(
+            "  {}  {}  {}",
+            format!("{:>index_width$}", i + 1).yellow(),
+            format!("{:<command_width$}", truncate(&proposal.command, command_width)).green(),
+            truncate(reason.lines().next().unwrap_or(""), reason_width).dimmed()
+        );
+    }
+    println!();
+}
+
+/// Shorten `text` to at most `max` characters, marking the cut with an ellipsis.
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let mut shortened: String = text.chars().take(max.saturating_sub(1)).collect();
+    shortened.push('…');
+    shortened
+}
+
+/// Show `text` in `$PAGER` (or `less -R`), returning false if no pager ran.
+fn page(text: &str) -> bool {
+    let pager = env::var("PAGER").ok().filter(|p| !p.trim().is_empty()).unwrap_or_else(|| "less -R".to_string());
+    // Go through sh so a PAGER with arguments works
+    let Ok(mut child) = Command::new("sh").arg("-c").arg(&pager).stdin(Stdio::piped()).spawn() else {
+        return false;
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // The user quitting the pager early closes the pipe; that's fine
+        let _ = stdin.write_all(text.as_bytes());
+    }
+    child.wait().is_ok_and(|status| status.success())
+}
+
+/// `text` after a blue `label`, wrapped to `width` with continuation lines
+/// indented to line up under the first.
+fn labelled(label: &str, text: &str, width: usize) -> String {
+    let indent = label.len() + 3;
+    let mut out = String::new();
+    for (i, line) in text.lines().flat_map(|line| wrap(line, width.saturating_sub(indent))).enumerate() {
+        if i == 0 {
+            out += &format!("  {} {}\n", label.blue(), line.dimmed());
+        } else {
+            out += &format!("{}{}\n", " ".repeat(indent), line.dimmed());
+        }
+    }
+    out
+}
+
+/// Break `line` at spaces into pieces of at most `width` characters. A word
+/// longer than `width` gets a piece of its own rather than being split.
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    let width = width.max(20);
+    let mut pieces = Vec::new();
+    let mut current: Option<String> = None;
+    // Splitting on single spaces keeps runs of spaces, e.g. inside quotes
+    for word in line.split(' ') {
+        match current.as_mut() {
+            Some(text) if text.chars().count() + 1 + word.chars().count() <= width => {
+                text.push(' ');
+                text.push_str(word);
+            }
+            Some(text) => pieces.push(std::mem::replace(text, word.to_string())),
+            None => current = Some(word.to_string()),
+        }
+    }
+    pieces.extend(current);
+    pieces
+}
+
+fn terminal_height() -> usize {
+    terminal_size::terminal_size()
+        .map(|(_, height)| usize::from(height.0))
+        .or_else(|| env::var("LINES").ok().and_then(|l| l.parse().ok()))
+        .filter(|h| *h > 0)
+        .unwrap_or(24)
+}
+
+fn terminal_width() -> usize {
+    terminal_size::terminal_size()
+        .map(|(width, _)| usize::from(width.0))
+        .or_else(|| env::var("COLUMNS").ok().and_then(|c| c.parse().ok()))
+        .filter(|w| *w > 0)
+        .unwrap_or(80)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_json_reads_plain_objects() {
+        let json = extract_json(r#"{"command": "ls -la"}"#).unwrap();
+        assert_eq!(json["command"], "ls -la");
+    }
+
+    #[test]
+    fn extract_json_unwraps_code_fences() {
+        let content = "```json\n{\"command\": \"du -sh .\"}\n```";
+        assert_eq!(extract_json(content).unwrap()["command"], "du -sh .");
+        let content = "```\n{\"command\": \"pwd\"}\n```\n";
+        assert_eq!(extract_json(content).unwrap()["command"], "pwd");
+    }
+
+    #[test]
+    fn extract_json_skips_surrounding_prose() {
+        let content = "Sure! Here you go:\n{\"command\": \"git status\"}\nLet me know if that helps {:}";
+        assert_eq!(extract_json(content).unwrap()["command"], "git status");
+    }
+
+    #[test]
+    fn extract_json_closes_truncated_objects() {
+        let json = extract_json(r#"{"command": "ls", "explanation": "lists files""#).unwrap();
+        assert_eq!(json["explanation"], "lists files");
+    }
+
+    #[test]
+    fn extract_json_ignores_braces_in_strings() {
+        let content = r#"Try this: {"command": "find . -name '*.tmp' -exec rm {} \\;"} and you're done."#;
+        assert_eq!(extract_json(content).unwrap()["command"], r"find . -name '*.tmp' -exec rm {} \;");
+    }
+
+    #[test]
+    fn strip_code_fence_leaves_unfenced_text() {
+        assert_eq!(strip_code_fence("{\"a\": 1}"), "{\"a\": 1}");
+        assert_eq!(strip_code_fence("```JSON\n{}\n```"), "{}");
+        assert_eq!(strip_code_fence("```json\n{\"a\": 1}"), "{\"a\": 1}");
+    }
+
+    #[test]
+    fn balanced_object_stops_at_the_matching_brace() {
+        assert_eq!(balanced_object(r#"{"a": {"b": 1}} trailing"#), Some(r#"{"a": {"b": 1}}"#));
+        assert_eq!(balanced_object(r#"{"a": "}\"{"} x"#), Some(r#"{"a": "}\"{"}"#));
+        assert_eq!(balanced_object(r#"{"a": 1"#), None);
+    }
+
+    #[test]
+    fn stream_content_joins_deltas() {
+        let content = "data: {\"choices\":[{\"delta\":{\"content\":\"{\\\"command\"}}]}\n\
+                       data: {\"choices\":[{\"delta\":{}}]}\n\
+                       data: {\"choices\":[{\"delta\":{\"content\":\"\\\": 1}\"}}]}\n\
+                       data: [DONE]\n";
+        assert_eq!(stream_content(content), "{\"command\": 1}");
+    }
+
+    #[test]
+    fn stream_content_reads_whole_message_events() {
+        let content = "data: {\"choices\":[{\"message\":{\"role\":\"assistant\",\"content\":\"{\\\"command\\\": \\\"uptime\\\"}\"}}]}\n\
+                       data: [DONE]\n";
+        assert_eq!(stream_content(content), "{\"command\": \"uptime\"}");
+        let proposals = parse_streaming_proposals(content);
+        assert_eq!(proposals.len(), 1);
+        assert_eq!(proposals[0].command, "uptime");
+    }
+
+    #[test]
+    fn substitute_trash_rewrites_rm_in_place() {
+        assert_eq!(substitute_trash("rm -rf build", "trash").as_deref(), Some("trash build"));
+        assert_eq!(substitute_trash("rm -f -- -odd", "trash").as_deref(), Some("trash -odd"));
+        assert_eq!(
+            substitute_trash("find . -name '*.o'  -delete; sudo rm -r  'my dir'", "trash-put").as_deref(),
+            Some("find . -name '*.o'  -delete; sudo trash-put  'my dir'")
+        );
+        assert_eq!(substitute_trash("cd /tmp&&rm -i a.txt|tee log", "trash").as_deref(), Some("cd /tmp&&trash a.txt|tee log"));
+    }
+
+    #[test]
+    fn substitute_trash_finds_statements_on_new_lines() {
+        assert_eq!(
+            substitute_trash("cd build\nrm -rf out\nif true; then rm x; fi", "trash").as_deref(),
+            Some("cd build\ntrash out\nif true; then trash x; fi")
+        );
+    }
+
+    #[test]
+    fn substitute_trash_leaves_other_commands_alone() {
+        assert_eq!(substitute_trash("echo rm -rf /", "trash"), None);
+        assert_eq!(substitute_trash("echo 'a; rm b'", "trash"), None);
+        assert_eq!(substitute_trash("rmdir build", "trash"), None);
+        assert_eq!(substitute_trash("git rm --cached file", "trash"), None);
+    }
+
+    fn chunks(parts: Vec<Result<&'static str, Error>>) -> impl futures_util::Stream<Item = Result<&'static [u8], Error>> {
+        futures_util::stream::iter(parts.into_iter().map(|part| part.map(str::as_bytes)))
+    }
+
+    #[tokio::test]
+    async fn read_completion_collects_the_whole_stream() {
+        let stream = chunks(vec![
+            Ok("data: {\"choices\":[{\"delta\":{\"content\":\"{\\\"command\\\": \"}}]}\n"),
+            Ok("data: {\"choices\":[{\"delta\":{\"content\":\"\\\"ls\\\"}\"}}]}\n"),
+            Ok("data: [DONE]\n"),
+        ]);
+        let mut seen = Vec::new();
+        let completion = read_completion(stream, |content| seen.push(content.to_string())).await.unwrap().unwrap();
+        assert!(!completion.interrupted);
+        assert_eq!(seen.last().map(String::as_str), Some("{\"command\": \"ls\"}"));
+        let proposals = completion_proposals(&completion).unwrap();
+        assert_eq!(proposals.len(), 1);
+        assert_eq!(proposals[0].command, "ls");
+        assert!(!proposals[0].incomplete);
+    }
+
+    #[tokio::test]
+    async fn read_completion_keeps_a_truncated_stream() {
+        let stream = chunks(vec![
+            Ok("data: {\"choices\":[{\"delta\":{\"content\":\"{\\\"command\\\": \\\"git log\\\"\"}}]}\n"),
+            Err(Error::Other("connection reset".to_string())),
+            Ok("data: {\"choices\":[{\"delta\":{\"content\":\"never read\"}}]}\n"),
+        ]);
+        let completion = read_completion(stream, |_| {}).await.unwrap().unwrap();
+        assert!(completion.interrupted);
+        let proposals = completion_proposals(&completion).unwrap();
+        assert_eq!(proposals.len(), 1);
+        assert_eq!(proposals[0].command, "git log");
+        assert!(proposals[0].incomplete);
+    }
+
+    #[tokio::test]
+    async fn read_completion_fails_when_nothing_arrived() {
+        let stream = chunks(vec![Err(Error::Other("connection reset".to_string()))]);
+        assert!(read_completion(stream, |_| {}).await.is_err());
+    }
+}
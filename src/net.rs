@@ -0,0 +1,180 @@
+use colored::*;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::error::Error;
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::budget;
+use crate::error;
+
+/// Delay before retrying a request whose host name failed to resolve.
+const DNS_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Turn on `--verbose` logging of requests, responses and auth decisions.
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+/// Print a diagnostic line to stderr when `--verbose` is on.
+pub fn log(message: impl std::fmt::Display) {
+    if VERBOSE.load(Ordering::Relaxed) {
+        eprintln!("{} {}", "[verbose]".dimmed(), message);
+    }
+}
+
+/// Header carrying the per-run request ID, for matching th's requests
+/// against server-side logs.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+static REQUEST_ID: OnceLock<String> = OnceLock::new();
+
+/// The ID sent with every chat request made by this run, generated on first use.
+pub fn request_id() -> &'static str {
+    REQUEST_ID.get_or_init(|| uuid::Uuid::new_v4().to_string())
+}
+
+/// The request ID, if this run has sent a chat request yet.
+pub fn sent_request_id() -> Option<&'static str> {
+    REQUEST_ID.get().map(String::as_str)
+}
+
+/// Token counts the API reported for a chat request.
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub prompt: u64,
+    pub completion: u64,
+}
+
+static USAGE: Mutex<Option<Usage>> = Mutex::new(None);
+
+/// Remember the token counts of the chat request that just finished.
+pub fn record_usage(usage: Usage) {
+    *USAGE.lock().unwrap() = Some(usage);
+}
+
+/// Token counts of the last chat request, if the API reported any.
+pub fn last_usage() -> Option<Usage> {
+    *USAGE.lock().unwrap()
+}
+
+/// Most attempts for a chat request that keeps failing transiently.
+pub const MAX_ATTEMPTS: u32 = 3;
+
+/// Statuses worth retrying: rate limits and temporary server trouble.
+pub fn is_transient_status(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 429 | 500 | 502 | 503)
+}
+
+/// Connection failures and timeouts, which may succeed on another try. Host
+/// names that didn't resolve were already retried by `send_with_retry`.
+pub fn is_transient_error(error: &error::Error) -> bool {
+    match error {
+        error::Error::Unreachable(unreachable) => !unreachable.dns,
+        error::Error::Network(e) => e.is_connect() || e.is_timeout(),
+        _ => false,
+    }
+}
+
+/// A request that never got a response because the host couldn't be
+/// reached. Displays as a short hint; the underlying error is its source and
+/// is logged under `--verbose`.
+#[derive(Debug)]
+pub struct Unreachable {
+    source: reqwest::Error,
+    dns: bool,
+}
+
+impl Unreachable {
+    fn new(source: reqwest::Error, dns: bool) -> Self {
+        log(format!("connection error: {:?}", source));
+        Self { source, dns }
+    }
+}
+
+impl std::fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let host = self.source.url().and_then(|url| url.host_str()).unwrap_or("github.com");
+        write!(f, "Can't reach {} — check your connection.", service_name(host))
+    }
+}
+
+impl Error for Unreachable {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// A readable name for the API behind `host`.
+fn service_name(host: &str) -> &str {
+    if host.ends_with("github.com") || host.ends_with("githubcopilot.com") {
+        "GitHub Copilot"
+    } else if host.ends_with("openai.com") {
+        "OpenAI"
+    } else if host.ends_with("anthropic.com") {
+        "Anthropic"
+    } else {
+        host
+    }
+}
+
+/// The delay the server asked for in a `Retry-After` header, in seconds.
+pub fn retry_after(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+}
+
+/// Exponential backoff before the given retry: 1s, 2s, 4s, ...
+pub fn backoff(retry: u32) -> Duration {
+    Duration::from_secs(1 << retry.saturating_sub(1).min(6))
+}
+
+pub trait RequestExt {
+    /// Send the request, retrying once if DNS resolution fails. Connection
+    /// failures and timeouts come back as `Unreachable`.
+    async fn send_with_retry(self) -> Result<Response, error::Error>;
+}
+
+impl RequestExt for RequestBuilder {
+    async fn send_with_retry(self) -> Result<Response, error::Error> {
+        let retry = self.try_clone();
+        let result = match self.send().await {
+            Err(e) if is_dns_error(&e) => {
+                let Some(retry) = retry else {
+                    return Err(Unreachable::new(e, true).into());
+                };
+                budget::spend(DNS_RETRY_DELAY)?;
+                tokio::time::sleep(DNS_RETRY_DELAY).await;
+                retry.send().await
+            }
+            result => result,
+        };
+        match result {
+            Err(e) if e.is_connect() || e.is_timeout() => {
+                let dns = is_dns_error(&e);
+                Err(Unreachable::new(e, dns).into())
+            }
+            result => Ok(result?),
+        }
+    }
+}
+
+fn is_dns_error(error: &reqwest::Error) -> bool {
+    if !error.is_connect() {
+        return false;
+    }
+    let mut source: Option<&dyn Error> = Some(error);
+    while let Some(err) = source {
+        let message = err.to_string().to_lowercase();
+        if message.contains("dns error") || message.contains("failed to lookup address") {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
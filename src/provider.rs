@@ -0,0 +1,70 @@
+use clap::ValueEnum;
+
+use crate::auth;
+
+/// The chat API that plans commands.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Provider {
+    /// GitHub Copilot, signed in with the device-code flow
+    #[default]
+    Copilot,
+    /// OpenAI, with an API key
+    Openai,
+    /// Anthropic's OpenAI-compatible endpoint, with an API key
+    Anthropic,
+}
+
+impl Provider {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        Self::from_str(name, true).map_err(|_| {
+            let names: Vec<String> = Self::value_variants()
+                .iter()
+                .filter_map(|p| p.to_possible_value().map(|v| v.get_name().to_string()))
+                .collect();
+            format!("provider must be one of: {}", names.join(", "))
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Provider::Copilot => "copilot",
+            Provider::Openai => "openai",
+            Provider::Anthropic => "anthropic",
+        }
+    }
+
+    /// Key the credentials are stored under in `auth.json`.
+    pub fn auth_key(self) -> &'static str {
+        match self {
+            Provider::Copilot => auth::PROVIDER,
+            Provider::Openai => "openai",
+            Provider::Anthropic => "anthropic",
+        }
+    }
+
+    /// The model used when none is configured.
+    pub fn default_model(self) -> &'static str {
+        match self {
+            Provider::Copilot | Provider::Openai => "gpt-4o",
+            Provider::Anthropic => "claude-sonnet-4-5",
+        }
+    }
+
+    /// The chat completions URL, under `api_base` when one is configured.
+    pub async fn chat_url(self, api_base: Option<&str>) -> String {
+        if let Some(base) = api_base {
+            return format!("{}/chat/completions", base);
+        }
+        match self {
+            Provider::Copilot => format!("{}/chat/completions", auth::copilot_api_base().await),
+            Provider::Openai => "https://api.openai.com/v1/chat/completions".to_string(),
+            Provider::Anthropic => "https://api.anthropic.com/v1/chat/completions".to_string(),
+        }
+    }
+
+    /// Whether the provider authenticates with a stored API key rather than
+    /// the Copilot login.
+    pub fn uses_api_key(self) -> bool {
+        self != Provider::Copilot
+    }
+}
@@ -0,0 +1,234 @@
+/// How dangerous a proposed command looks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Risk {
+    Safe,
+    /// The command matched a dangerous pattern; the reason says which.
+    Dangerous(String),
+}
+
+/// Scan a command for destructive operations. `extra_patterns` are matched
+/// as plain substrings on top of the built-in checks.
+pub fn risk_level(command: &str, extra_patterns: &[String]) -> Risk {
+    let compact: String = command.chars().filter(|c| !c.is_whitespace()).collect();
+    if compact.contains(":(){:|:&};:") {
+        return Risk::Dangerous("fork bomb".to_string());
+    }
+    if compact.contains(">/dev/sd") || compact.contains(">/dev/nvme") {
+        return Risk::Dangerous("overwrites a block device".to_string());
+    }
+    if let Some(pattern) = extra_patterns.iter().find(|p| !p.is_empty() && command.contains(p.as_str())) {
+        return Risk::Dangerous(format!("matches the configured pattern `{}`", pattern));
+    }
+    for segment in command.split(['\n', ';', '&', '|']) {
+        let words: Vec<String> = segment
+            .split_whitespace()
+            .map(|w| w.trim_matches(|c| c == '"' || c == '\''))
+            .map(str::to_string)
+            .collect();
+        if let Some(reason) = statement_risk(strip_prefixes(&words)) {
+            return Risk::Dangerous(reason);
+        }
+    }
+    if pipes_download_into_shell(command) {
+        return Risk::Dangerous("pipes a download straight into a shell".to_string());
+    }
+    Risk::Safe
+}
+
+/// Whether `command` is covered by one of the `trusted` prefixes and may run
+/// without asking. A prefix matches whole words only, so `rm` trusts
+/// `rm notes.txt` but not `rmdir build`, and `git status` trusts
+/// `git status -s` but not `git stash`. Only a single plain statement
+/// qualifies: anything chained, piped, redirected or using command
+/// substitution always asks.
+pub fn is_trusted(command: &str, trusted: &[String]) -> bool {
+    let command = command.trim();
+    if command.is_empty() || command.contains(['\n', ';', '&', '|', '>', '<', '`']) || command.contains("$(") {
+        return false;
+    }
+    let words: Vec<&str> = command.split_whitespace().collect();
+    trusted.iter().any(|prefix| {
+        let prefix: Vec<&str> = prefix.split_whitespace().collect();
+        !prefix.is_empty() && words.starts_with(&prefix)
+    })
+}
+
+/// Skip wrappers such as `sudo` that don't change what the command does.
+fn strip_prefixes(words: &[String]) -> &[String] {
+    let mut words = words;
+    while let Some(first) = words.first()
+        && matches!(first.as_str(), "sudo" | "doas" | "env" | "nohup" | "time" | "command" | "exec")
+    {
+        words = &words[1..];
+    }
+    words
+}
+
+fn statement_risk(words: &[String]) -> Option<String> {
+    let (program, args) = words.split_first()?;
+    let program = program.rsplit('/').next().unwrap_or(program);
+    match program {
+        "rm" => {
+            let flags: String = args
+                .iter()
+                .filter(|a| a.starts_with('-') && !a.starts_with("--"))
+                .map(|a| a.trim_start_matches('-'))
+                .collect();
+            let recursive = flags.contains(['r', 'R']) || args.iter().any(|a| a == "--recursive");
+            let target = args.iter().filter(|a| !a.starts_with('-')).find(|a| is_sensitive_path(a))?;
+            recursive.then(|| format!("recursively deletes {}", target))
+        }
+        "dd" => args
+            .iter()
+            .any(|a| a.starts_with("of=/dev/") && !a.starts_with("of=/dev/null"))
+            .then(|| "dd writes directly to a device".to_string()),
+        "shred" | "wipefs" => Some(format!("{} irreversibly destroys data", program)),
+        _ if program.starts_with("mkfs") => Some("formats a filesystem".to_string()),
+        "chmod" | "chown" | "chgrp" => {
+            let recursive = args.iter().any(|a| a == "-R" || a == "--recursive");
+            let target = args.iter().skip(1).find(|a| is_sensitive_path(a))?;
+            recursive.then(|| format!("recursively changes ownership or permissions of {}", target))
+        }
+        "git" => {
+            let has = |flag: &str| args.iter().any(|a| a == flag);
+            match args.first().map(String::as_str) {
+                Some("reset") if has("--hard") => Some("git reset --hard discards uncommitted changes".to_string()),
+                Some("clean") if has("--force") || args.iter().any(|a| a.starts_with('-') && !a.starts_with("--") && a.contains('f')) => {
+                    Some("git clean deletes untracked files".to_string())
+                }
+                Some("push") if has("--force") || has("-f") => Some("force-push rewrites remote history".to_string()),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Paths whose recursive removal or modification is almost never intended:
+/// the root, top-level system directories, the home directory and the
+/// current or parent directory as a whole.
+fn is_sensitive_path(path: &str) -> bool {
+    let path = path.trim_end_matches('/');
+    if path.is_empty() || matches!(path, "~" | "$HOME" | "${HOME}" | "." | ".." | "*" | "./*" | "~/*" | "$HOME/*") {
+        return true;
+    }
+    // `/etc`, `/usr`, `/*` and the like, but not deeper paths such as `/tmp/build`
+    path.starts_with('/') && !path[1..].contains('/')
+}
+
+fn pipes_download_into_shell(command: &str) -> bool {
+    let mut previous_downloads = false;
+    for stage in command.split('|').map(str::trim) {
+        let program = stage.split_whitespace().find(|w| !matches!(*w, "sudo" | "env")).unwrap_or("");
+        let program = program.rsplit('/').next().unwrap_or(program);
+        if previous_downloads && matches!(program, "sh" | "bash" | "zsh" | "dash") {
+            return true;
+        }
+        previous_downloads = matches!(program, "curl" | "wget");
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dangerous(command: &str) -> bool {
+        matches!(risk_level(command, &[]), Risk::Dangerous(_))
+    }
+
+    #[test]
+    fn flags_destructive_commands() {
+        for command in [
+            "rm -rf /",
+            "rm -rf ~",
+            "sudo rm -r /etc",
+            "rm -fr $HOME",
+            "rm --recursive --force /usr/",
+            "cd /tmp && rm -rf *",
+            "dd if=/dev/zero of=/dev/sda",
+            "mkfs.ext4 /dev/sdb1",
+            "shred secrets.txt",
+            "chmod -R 777 /",
+            "git reset --hard",
+            "git clean -fd",
+            "git push --force origin main",
+            "curl -fsSL https://example.com/install.sh | sh",
+            ":(){ :|:& };:",
+            "echo data > /dev/sda",
+        ] {
+            assert!(dangerous(command), "{} should be dangerous", command);
+        }
+    }
+
+    #[test]
+    fn leaves_benign_lookalikes_alone() {
+        for command in [
+            "rm -rf ./build",
+            "rm -rf /tmp/build",
+            "rm notes.txt",
+            "rm -r target/debug",
+            "rmdir /",
+            "dd if=in.img of=/dev/null",
+            "chmod 644 /etc/hosts",
+            "chmod -R u+w ./src",
+            "git reset --soft HEAD~1",
+            "git clean -n",
+            "git push origin main",
+            "curl -O https://example.com/install.sh",
+            "echo rm -rf / > notes.txt",
+        ] {
+            assert!(!dangerous(command), "{} should be safe", command);
+        }
+    }
+
+    #[test]
+    fn matches_configured_patterns() {
+        let patterns = vec!["kubectl delete".to_string()];
+        assert!(matches!(risk_level("kubectl delete pod web", &patterns), Risk::Dangerous(_)));
+        assert_eq!(risk_level("kubectl get pods", &patterns), Risk::Safe);
+    }
+
+    fn trusted(prefixes: &[&str]) -> Vec<String> {
+        prefixes.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn trusts_whole_word_prefixes() {
+        let list = trusted(&["ls", "git status", "rm"]);
+        assert!(is_trusted("ls", &list));
+        assert!(is_trusted("ls -la /tmp", &list));
+        assert!(is_trusted("git status --short", &list));
+        assert!(is_trusted("rm notes.txt", &list));
+        assert!(!is_trusted("rmdir build", &list));
+        assert!(!is_trusted("lsof -i :80", &list));
+        assert!(!is_trusted("git stash", &list));
+        assert!(!is_trusted("git", &list));
+    }
+
+    #[test]
+    fn never_trusts_compound_commands() {
+        let list = trusted(&["ls", "echo", "cat"]);
+        for command in [
+            "ls; rm -rf ~",
+            "ls && rm -rf ~",
+            "ls || reboot",
+            "ls | sh",
+            "echo hi > ~/.bashrc",
+            "cat < /etc/shadow",
+            "echo $(rm -rf ~)",
+            "echo `reboot`",
+            "ls &",
+            "ls\nrm -rf ~",
+        ] {
+            assert!(!is_trusted(command, &list), "{} should not be trusted", command);
+        }
+    }
+
+    #[test]
+    fn trusts_nothing_with_an_empty_list() {
+        assert!(!is_trusted("ls", &[]));
+        assert!(!is_trusted("", &trusted(&["ls"])));
+        assert!(!is_trusted("ls", &trusted(&["  "])));
+    }
+}
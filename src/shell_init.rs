@@ -0,0 +1,49 @@
+use clap::ValueEnum;
+
+/// Environment variable the shell wrapper sets to a temp file. When present,
+/// commands that must run in the calling shell are written there for the
+/// wrapper to `eval` instead of being executed in a subshell.
+pub const EVAL_FILE_ENV: &str = "TH_EVAL_FILE";
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+const POSIX_FUNCTION: &str = r#"th() {
+    local th_eval_file th_status
+    th_eval_file="$(mktemp "${TMPDIR:-/tmp}/th-eval.XXXXXX")" || return
+    TH_EVAL_FILE="$th_eval_file" command th "$@"
+    th_status=$?
+    if [ -s "$th_eval_file" ]; then
+        eval "$(cat "$th_eval_file")"
+        th_status=$?
+    fi
+    rm -f "$th_eval_file"
+    return $th_status
+}
+"#;
+
+const FISH_FUNCTION: &str = r#"function th
+    set -l th_eval_file (mktemp)
+    or return
+    TH_EVAL_FILE=$th_eval_file command th $argv
+    set -l th_status $status
+    if test -s $th_eval_file
+        eval (cat $th_eval_file | string collect)
+        set th_status $status
+    end
+    rm -f $th_eval_file
+    return $th_status
+end
+"#;
+
+/// The wrapper function to source from the user's shell startup file.
+pub fn script(shell: Shell) -> &'static str {
+    match shell {
+        Shell::Bash | Shell::Zsh => POSIX_FUNCTION,
+        Shell::Fish => FISH_FUNCTION,
+    }
+}
@@ -0,0 +1,90 @@
+/// Splits a server-sent event stream into lines as network chunks arrive.
+/// Bytes after the last newline are held back until the rest of the line
+/// shows up, so neither an event nor a multi-byte character is ever cut in
+/// half at a chunk boundary.
+#[derive(Debug, Default)]
+pub struct LineBuffer {
+    pending: Vec<u8>,
+}
+
+impl LineBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a chunk and return the lines it completes, without their line
+    /// endings.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.pending.extend_from_slice(chunk);
+        let Some(end) = self.pending.iter().rposition(|&b| b == b'\n') else {
+            return Vec::new();
+        };
+        let rest = self.pending.split_off(end + 1);
+        let complete = std::mem::replace(&mut self.pending, rest);
+        String::from_utf8_lossy(&complete)
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// The unterminated last line, once the stream has ended.
+    pub fn finish(self) -> Option<String> {
+        (!self.pending.is_empty()).then(|| String::from_utf8_lossy(&self.pending).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `chunks` in order and collect every completed line, plus the
+    /// unterminated remainder.
+    fn split(chunks: &[&[u8]]) -> (Vec<String>, Option<String>) {
+        let mut buffer = LineBuffer::new();
+        let lines = chunks.iter().flat_map(|chunk| buffer.push(chunk)).collect();
+        (lines, buffer.finish())
+    }
+
+    #[test]
+    fn whole_lines_pass_straight_through() {
+        let (lines, rest) = split(&[b"data: {\"a\":1}\n\ndata: [DONE]\n"]);
+        assert_eq!(lines, ["data: {\"a\":1}", "", "data: [DONE]"]);
+        assert_eq!(rest, None);
+    }
+
+    #[test]
+    fn holds_back_a_line_split_mid_json() {
+        let (lines, rest) = split(&[b"data: {\"choices\":[{\"del", b"ta\":{\"content\":\"ls\"}}]}\n"]);
+        assert_eq!(lines, ["data: {\"choices\":[{\"delta\":{\"content\":\"ls\"}}]}"]);
+        assert_eq!(rest, None);
+    }
+
+    #[test]
+    fn holds_back_a_line_split_mid_prefix() {
+        let (lines, rest) = split(&[b"da", b"ta", b": {}\nd", b"ata: [DONE]\n"]);
+        assert_eq!(lines, ["data: {}", "data: [DONE]"]);
+        assert_eq!(rest, None);
+    }
+
+    #[test]
+    fn never_cuts_a_multibyte_character() {
+        let text = "data: {\"content\":\"ünïcødé\"}\n".as_bytes();
+        let chunks: Vec<&[u8]> = text.chunks(1).collect();
+        let (lines, rest) = split(&chunks);
+        assert_eq!(lines, ["data: {\"content\":\"ünïcødé\"}"]);
+        assert_eq!(rest, None);
+    }
+
+    #[test]
+    fn strips_crlf_line_endings() {
+        let (lines, _) = split(&[b"data: {}\r", b"\ndata: [DONE]\r\n"]);
+        assert_eq!(lines, ["data: {}", "data: [DONE]"]);
+    }
+
+    #[test]
+    fn finish_returns_the_unterminated_last_line() {
+        let (lines, rest) = split(&[b"data: {}\ndata: [DO", b"NE]"]);
+        assert_eq!(lines, ["data: {}"]);
+        assert_eq!(rest.as_deref(), Some("data: [DONE]"));
+    }
+}